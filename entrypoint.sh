@@ -1,12 +1,13 @@
 #!/bin/sh
-# Optional sanity checks – remove if you don't need them.
-: "${HOST:?HOST not set}"
-: "${PORT:?PORT not set}"
-: "${UPSTREAM:?UPSTREAM not set}"
-: "${METRICS_URL:?METRICS_URL not set}"
+# HOST and PORT override `listen_addr` only when set, so the address in a
+# mounted config file is kept. Without a config file, listen on port 4000.
+[ -z "$CONFIG" ] && [ -z "$PORT" ] && PORT=4000
 
-exec /lm-proxy \
-    --host "$HOST" \
-    --port "$PORT" \
-    --upstream "$UPSTREAM" \
-    --metrics-url "$METRICS_URL"
+set --
+[ -n "$HOST" ] && set -- "$@" --host "$HOST"
+[ -n "$PORT" ] && set -- "$@" --port "$PORT"
+[ -n "$CONFIG" ] && set -- "$@" --config "$CONFIG"
+[ -n "$UPSTREAM" ] && set -- "$@" --upstream "$UPSTREAM"
+[ -n "$METRICS_URL" ] && set -- "$@" --metrics-url "$METRICS_URL"
+
+exec /lm-proxy "$@"
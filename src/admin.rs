@@ -0,0 +1,46 @@
+use crate::auth;
+use crate::handler::{self, ProxyService};
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+    Json,
+};
+
+/// Guards the admin endpoints and `/metrics`. With an
+/// `admin_key` they require it; without one they're open, unless virtual keys
+/// are configured, since they show key names and per-key traffic.
+pub async fn require_admin_key(State(proxy): State<ProxyService>, request: Request, next: Next) -> Response {
+    let active = proxy.active_config();
+    let Some(admin_key) = &active.config.admin_key else {
+        if active.config.virtual_keys.is_empty() {
+            return next.run(request).await;
+        }
+        return handler::error_response(
+            StatusCode::FORBIDDEN,
+            "Admin endpoints are disabled while virtual keys are configured without an `admin_key`.",
+            "invalid_request_error",
+            None,
+        );
+    };
+    match auth::authenticate_admin(admin_key, request.headers()) {
+        Ok(()) => next.run(request).await,
+        Err(e) => handler::error_response(StatusCode::UNAUTHORIZED, &e.to_string(), "invalid_request_error", e.code()),
+    }
+}
+
+/// Returns the active config version and its contents with credentials redacted
+pub async fn config_handler(State(proxy): State<ProxyService>) -> Json<serde_json::Value> {
+    let active = proxy.active_config();
+    Json(serde_json::json!({
+        "version": active.version,
+        "loaded_at": active.loaded_at,
+        "config": active.config.redacted(),
+    }))
+}
+
+/// Returns circuit breaker state and in-flight request counts for each upstream
+pub async fn upstreams_handler(State(proxy): State<ProxyService>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "upstreams": proxy.upstream_statuses() }))
+}
@@ -0,0 +1,83 @@
+//! Bounded queues that background tasks drain in batches, used for metric
+//! events and trace spans so requests never wait on an export.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Items waiting to be sent, up to a limit
+pub struct BatchQueue<T> {
+    items: Mutex<VecDeque<T>>,
+    /// Signalled when a full batch is waiting
+    batch_ready: Notify,
+    /// What the queue holds, for logs, e.g. `spans`
+    what: &'static str,
+    dropped: AtomicU64,
+}
+
+impl<T> BatchQueue<T> {
+    pub fn new(what: &'static str) -> Self {
+        Self {
+            items: Mutex::new(VecDeque::new()),
+            batch_ready: Notify::new(),
+            what,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue an item. Never blocks; returns false without queueing it if
+    /// `queue_size` items are already waiting.
+    pub fn push(&self, item: T, queue_size: usize, batch_size: usize) -> bool {
+        let mut items = self.items.lock().unwrap();
+        if items.len() >= queue_size {
+            return false;
+        }
+        items.push_back(item);
+        if items.len() >= batch_size {
+            self.batch_ready.notify_one();
+        }
+        true
+    }
+
+    /// Items waiting to be sent
+    pub fn len(&self) -> usize {
+        self.items.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove up to `batch_size` items, oldest first
+    pub fn take_batch(&self, batch_size: usize) -> Vec<T> {
+        let mut items = self.items.lock().unwrap();
+        let count = batch_size.min(items.len());
+        items.drain(..count).collect()
+    }
+
+    /// Log items that were dropped instead of sent
+    pub fn record_dropped(&self, reason: &'static str, count: u64) {
+        let before = self.dropped.fetch_add(count, Ordering::Relaxed);
+        let after = before + count;
+        // Warn on the first drop and then every thousand
+        if before == 0 || before / 1000 != after / 1000 {
+            log::warn!("Dropped {} {} so far ({})", after, self.what, reason);
+        }
+    }
+}
+
+/// Call `flush` whenever a batch fills up or the flush interval passes. The
+/// interval is read before each wait, so config reloads apply as they happen.
+pub async fn run<T, F: Future<Output = ()>>(
+    queue: &BatchQueue<T>,
+    flush_interval: impl Fn() -> Duration,
+    flush: impl Fn() -> F,
+) {
+    loop {
+        let _ = tokio::time::timeout(flush_interval(), queue.batch_ready.notified()).await;
+        flush().await;
+    }
+}
@@ -0,0 +1,449 @@
+use crate::config::{BudgetLimitConfig, BudgetPeriod};
+use crate::dates;
+use crate::handler::ProxyService;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// How often budget usage is written to the state file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Token and spend usage per virtual key and team for each budget period. Usage is
+/// counted for every key whether or not it has limits, so a limit added by a
+/// reload applies to usage earlier in the period.
+#[derive(Default)]
+pub struct BudgetTracker {
+    /// Keyed by subject and period, e.g. `key:ci/daily`
+    counters: Mutex<BTreeMap<String, Counter>>,
+    dirty: AtomicBool,
+    /// The state file usage was loaded from and is saved to. Only set by
+    /// `load`, so a file that failed to load is never overwritten.
+    state_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Counter {
+    /// The period the count is for, e.g. `2026-10-16`; the count resets when it changes
+    period: String,
+    tokens: u64,
+    /// Cost of the period's requests to models with a price
+    #[serde(default)]
+    cost_usd: f64,
+    #[serde(default)]
+    soft_limit_alerted: bool,
+    #[serde(default)]
+    cost_soft_limit_alerted: bool,
+}
+
+/// A hard limit that rejects new requests
+#[derive(Debug, PartialEq)]
+pub struct BudgetExceeded {
+    pub subject: String,
+    pub period: BudgetPeriod,
+    pub limit: BudgetLimit,
+}
+
+/// The amount a hard limit allows
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BudgetLimit {
+    Tokens(u64),
+    CostUsd(f64),
+}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let period = period_name(self.period);
+        match self.limit {
+            BudgetLimit::Tokens(tokens) => {
+                write!(f, "{} token budget of {} for {} is used up", period, tokens, self.subject)
+            }
+            BudgetLimit::CostUsd(cost) => {
+                write!(f, "{} spend budget of ${:.2} for {} is used up", period, cost, self.subject)
+            }
+        }
+    }
+}
+
+/// A soft limit crossed by a request's usage, sent to `budgets.alert_url`
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SoftLimitAlert {
+    pub subject: String,
+    pub period: BudgetPeriod,
+    #[serde(flatten)]
+    pub usage: SoftLimitUsage,
+}
+
+/// Usage so far and the soft limit it reached
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum SoftLimitUsage {
+    Tokens { used_tokens: u64, limit_tokens: u64 },
+    CostUsd { used_cost_usd: f64, limit_cost_usd: f64 },
+}
+
+impl fmt::Display for SoftLimitAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let period = period_name(self.period);
+        match self.usage {
+            SoftLimitUsage::Tokens { used_tokens, limit_tokens } => write!(
+                f,
+                "{} used {} of its {} soft limit of {} tokens",
+                self.subject, used_tokens, period, limit_tokens
+            ),
+            SoftLimitUsage::CostUsd { used_cost_usd, limit_cost_usd } => write!(
+                f,
+                "{} spent ${:.2} of its {} soft limit of ${:.2}",
+                self.subject, used_cost_usd, period, limit_cost_usd
+            ),
+        }
+    }
+}
+
+impl BudgetTracker {
+    /// Load saved usage, starting empty if the file doesn't exist yet. Usage
+    /// is saved back to the same file by `flush`.
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let counters = match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            counters: Mutex::new(counters),
+            dirty: AtomicBool::new(false),
+            state_file: Some(path.to_path_buf()),
+        })
+    }
+
+    /// Check the hard limits that apply to a key and its team before a request
+    pub fn check(
+        &self,
+        limits: &[BudgetLimitConfig],
+        key: &str,
+        team: Option<&str>,
+        now: u64,
+    ) -> Result<(), BudgetExceeded> {
+        let counters = self.counters.lock().unwrap();
+        for limit in applicable(limits, key, team) {
+            let subject = subject(limit);
+            let counter = counters
+                .get(&counter_key(&subject, limit.period))
+                .filter(|counter| counter.period == period_id(limit.period, now));
+            let used_tokens = counter.map_or(0, |counter| counter.tokens);
+            let used_cost = counter.map_or(0.0, |counter| counter.cost_usd);
+            let exceeded = match (limit.hard_limit_tokens, limit.max_cost_usd) {
+                (Some(tokens), _) if used_tokens >= tokens => BudgetLimit::Tokens(tokens),
+                (_, Some(cost)) if used_cost >= cost => BudgetLimit::CostUsd(cost),
+                _ => continue,
+            };
+            return Err(BudgetExceeded {
+                subject: describe(&subject),
+                period: limit.period,
+                limit: exceeded,
+            });
+        }
+        Ok(())
+    }
+
+    /// Add a request's tokens and cost to its key's and team's budgets.
+    /// Returns the soft limits the usage crossed, each reported once per period.
+    pub fn record(
+        &self,
+        limits: &[BudgetLimitConfig],
+        key: &str,
+        team: Option<&str>,
+        tokens: u64,
+        cost_usd: f64,
+        now: u64,
+    ) -> Vec<SoftLimitAlert> {
+        let mut counters = self.counters.lock().unwrap();
+        let subjects = std::iter::once(format!("key:{}", key)).chain(team.map(|team| format!("team:{}", team)));
+        for subject in subjects {
+            for period in [BudgetPeriod::Daily, BudgetPeriod::Monthly, BudgetPeriod::Lifetime] {
+                let current = period_id(period, now);
+                let counter = counters.entry(counter_key(&subject, period)).or_default();
+                if counter.period != current {
+                    *counter = Counter {
+                        period: current,
+                        ..Default::default()
+                    };
+                }
+                counter.tokens += tokens;
+                counter.cost_usd += cost_usd;
+            }
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+
+        let mut alerts = vec![];
+        for limit in applicable(limits, key, team) {
+            let subject = subject(limit);
+            let Some(counter) = counters.get_mut(&counter_key(&subject, limit.period)) else {
+                continue;
+            };
+            let mut alert = |usage| {
+                alerts.push(SoftLimitAlert {
+                    subject: describe(&subject),
+                    period: limit.period,
+                    usage,
+                })
+            };
+            // Token and spend soft limits alert separately, each once per period
+            if let Some(limit_tokens) = limit.soft_limit_tokens
+                && counter.tokens >= limit_tokens
+                && !counter.soft_limit_alerted
+            {
+                counter.soft_limit_alerted = true;
+                alert(SoftLimitUsage::Tokens {
+                    used_tokens: counter.tokens,
+                    limit_tokens,
+                });
+            }
+            if let Some(limit_cost_usd) = limit.soft_limit_cost_usd
+                && counter.cost_usd >= limit_cost_usd
+                && !counter.cost_soft_limit_alerted
+            {
+                counter.cost_soft_limit_alerted = true;
+                alert(SoftLimitUsage::CostUsd {
+                    used_cost_usd: counter.cost_usd,
+                    limit_cost_usd,
+                });
+            }
+        }
+        alerts
+    }
+
+    /// The file usage is saved to, if it was loaded from one
+    pub fn state_file(&self) -> Option<&Path> {
+        self.state_file.as_deref()
+    }
+
+    /// Write usage to the state file if it changed since the last flush
+    pub fn flush(&self) -> std::io::Result<()> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let bytes = serde_json::to_vec_pretty(&*self.counters.lock().unwrap())?;
+
+        // Write to a temporary file first so a crash never leaves a partial file
+        let tmp = path.with_extension("tmp");
+        let result = std::fs::write(&tmp, bytes).and_then(|_| std::fs::rename(&tmp, path));
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+/// Periodically save budget usage to the configured state file
+pub async fn run(proxy: ProxyService) {
+    let mut ticker = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        ticker.tick().await;
+        flush(&proxy);
+    }
+}
+
+/// Save budget usage now, e.g. before shutting down
+pub fn flush(proxy: &ProxyService) {
+    let budgets = proxy.budgets();
+    if let Some(path) = budgets.state_file()
+        && let Err(e) = budgets.flush()
+    {
+        log::error!("Failed to save budgets to {}: {}", path.display(), e);
+    }
+}
+
+/// POST a soft limit alert to the configured URL
+pub fn send_alert(client: reqwest::Client, url: String, alert: SoftLimitAlert) {
+    tokio::spawn(async move {
+        if let Err(e) = client.post(&url).json(&alert).send().await {
+            log::error!("Failed to send budget alert: {}", e);
+        }
+    });
+}
+
+fn applicable<'a>(
+    limits: &'a [BudgetLimitConfig],
+    key: &'a str,
+    team: Option<&'a str>,
+) -> impl Iterator<Item = &'a BudgetLimitConfig> {
+    limits.iter().filter(move |limit| {
+        limit.key.as_deref() == Some(key) || (limit.team.is_some() && limit.team.as_deref() == team)
+    })
+}
+
+fn subject(limit: &BudgetLimitConfig) -> String {
+    match (&limit.key, &limit.team) {
+        (Some(key), _) => format!("key:{}", key),
+        (None, Some(team)) => format!("team:{}", team),
+        (None, None) => String::new(),
+    }
+}
+
+/// `key:ci` -> "key `ci`"
+fn describe(subject: &str) -> String {
+    let (kind, name) = subject.split_once(':').unwrap_or(("key", subject));
+    format!("{} `{}`", kind, name)
+}
+
+fn counter_key(subject: &str, period: BudgetPeriod) -> String {
+    format!("{}/{}", subject, period_name(period).to_lowercase())
+}
+
+fn period_name(period: BudgetPeriod) -> &'static str {
+    match period {
+        BudgetPeriod::Daily => "Daily",
+        BudgetPeriod::Monthly => "Monthly",
+        BudgetPeriod::Lifetime => "Lifetime",
+    }
+}
+
+/// Identifies the period containing `now` (unix seconds, UTC)
+fn period_id(period: BudgetPeriod, now: u64) -> String {
+    let (year, month, day) = dates::civil_from_days(now / dates::SECS_PER_DAY);
+    match period {
+        BudgetPeriod::Daily => format!("{:04}-{:02}-{:02}", year, month, day),
+        BudgetPeriod::Monthly => format!("{:04}-{:02}", year, month),
+        BudgetPeriod::Lifetime => "lifetime".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2026-10-16T12:00:00Z
+    const NOW: u64 = 1_792_152_000;
+    const DAY: u64 = 86_400;
+
+    fn limits() -> Vec<BudgetLimitConfig> {
+        vec![
+            BudgetLimitConfig {
+                key: Some("ci".to_string()),
+                team: None,
+                period: BudgetPeriod::Daily,
+                soft_limit_tokens: Some(80),
+                hard_limit_tokens: Some(100),
+                soft_limit_cost_usd: None,
+                max_cost_usd: None,
+            },
+            BudgetLimitConfig {
+                key: None,
+                team: Some("platform".to_string()),
+                period: BudgetPeriod::Monthly,
+                soft_limit_tokens: None,
+                hard_limit_tokens: Some(150),
+                soft_limit_cost_usd: None,
+                max_cost_usd: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_period_id() {
+        assert_eq!(period_id(BudgetPeriod::Daily, NOW), "2026-10-16");
+        assert_eq!(period_id(BudgetPeriod::Monthly, NOW), "2026-10");
+        assert_eq!(period_id(BudgetPeriod::Daily, 0), "1970-01-01");
+        assert_eq!(period_id(BudgetPeriod::Daily, 951_782_400), "2000-02-29");
+    }
+
+    #[test]
+    fn test_hard_and_soft_limits() {
+        let tracker = BudgetTracker::default();
+        let limits = limits();
+
+        assert_eq!(tracker.record(&limits, "ci", Some("platform"), 50, 0.0, NOW), vec![]);
+        let alerts = tracker.record(&limits, "ci", Some("platform"), 40, 0.0, NOW);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].to_string(), "key `ci` used 90 of its Daily soft limit of 80 tokens");
+        // Each soft limit alerts once per period
+        assert_eq!(tracker.record(&limits, "ci", Some("platform"), 10, 0.0, NOW), vec![]);
+
+        let err = tracker.check(&limits, "ci", Some("platform"), NOW).unwrap_err();
+        assert_eq!(err.to_string(), "Daily token budget of 100 for key `ci` is used up");
+
+        // The daily budget resets the next day, but the team's monthly one doesn't
+        assert!(tracker.check(&limits, "ci", Some("platform"), NOW + DAY).is_ok());
+        tracker.record(&limits, "other", Some("platform"), 50, 0.0, NOW + DAY);
+        let err = tracker.check(&limits, "ci", Some("platform"), NOW + DAY).unwrap_err();
+        assert_eq!(err.subject, "team `platform`");
+    }
+
+    #[test]
+    fn test_spend_limits() {
+        let tracker = BudgetTracker::default();
+        let limits = vec![BudgetLimitConfig {
+            key: Some("ci".to_string()),
+            team: None,
+            period: BudgetPeriod::Lifetime,
+            soft_limit_tokens: Some(25),
+            hard_limit_tokens: None,
+            soft_limit_cost_usd: Some(4.0),
+            max_cost_usd: Some(5.0),
+        }];
+
+        let alerts = tracker.record(&limits, "ci", None, 10, 3.0, NOW);
+        assert_eq!(alerts, vec![]);
+        assert!(tracker.check(&limits, "ci", None, NOW).is_ok());
+        // Requests to models without a price don't count towards spend
+        let alerts = tracker.record(&limits, "ci", None, 20, 0.0, NOW);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].to_string(), "key `ci` used 30 of its Lifetime soft limit of 25 tokens");
+        assert!(tracker.check(&limits, "ci", None, NOW).is_ok());
+
+        // Reaching the spend soft limit alerts even though the token one already has
+        let alerts = tracker.record(&limits, "ci", None, 10, 1.5, NOW);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].to_string(), "key `ci` spent $4.50 of its Lifetime soft limit of $4.00");
+        assert_eq!(
+            serde_json::to_value(&alerts[0]).unwrap(),
+            serde_json::json!({
+                "subject": "key `ci`",
+                "period": "lifetime",
+                "used_cost_usd": 4.5,
+                "limit_cost_usd": 4.0,
+            })
+        );
+        assert!(tracker.check(&limits, "ci", None, NOW).is_ok());
+
+        tracker.record(&limits, "ci", None, 10, 1.0, NOW + 365 * DAY);
+        let err = tracker.check(&limits, "ci", None, NOW + 365 * DAY).unwrap_err();
+        assert_eq!(err.limit, BudgetLimit::CostUsd(5.0));
+        assert_eq!(err.to_string(), "Lifetime spend budget of $5.00 for key `ci` is used up");
+    }
+
+    #[test]
+    fn test_usage_survives_reload() {
+        let path = std::env::temp_dir().join(format!("lm-proxy-budgets-{}.json", std::process::id()));
+        let limits = limits();
+
+        let tracker = BudgetTracker::load(&path).unwrap();
+        tracker.record(&limits, "ci", None, 100, 6.0, NOW);
+        tracker.flush().unwrap();
+
+        let reloaded = BudgetTracker::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(reloaded.check(&limits, "ci", None, NOW).is_err());
+        let spend_limit = BudgetLimitConfig {
+            hard_limit_tokens: None,
+            max_cost_usd: Some(5.0),
+            ..limits[0].clone()
+        };
+        let err = reloaded.check(&[spend_limit], "ci", None, NOW).unwrap_err();
+        assert_eq!(err.limit, BudgetLimit::CostUsd(5.0));
+
+        // A file that can't be parsed is an error rather than empty usage,
+        // which would be saved over it
+        std::fs::write(&path, "not json").unwrap();
+        let result = BudgetTracker::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
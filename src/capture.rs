@@ -0,0 +1,754 @@
+//! Full request and response capture for auditing. Each captured request is
+//! appended to a JSONL file as one line, with credentials redacted and
+//! streamed responses reassembled into the message the client ended up with.
+
+use crate::config::CaptureConfig;
+use crate::dates::{self, SECS_PER_DAY};
+use crate::models::Usage;
+use crate::sse;
+use crate::writer::{BackgroundWriter, BatchWrite};
+use axum::http::{HeaderMap, Method, Uri};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Replaces the value of a redacted header
+const REDACTED: &str = "***";
+
+/// Headers that are always redacted
+const CREDENTIAL_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "x-api-key",
+    "api-key",
+    "cookie",
+    "set-cookie",
+];
+
+/// Query parameters that are always redacted, e.g. Azure's `api-key` or
+/// Gemini's `key`
+const CREDENTIAL_PARAMS: &[&str] = &["key", "api-key", "api_key", "apikey", "access_token", "token"];
+
+/// Most records written to the file before it's flushed
+const BATCH_SIZE: usize = 100;
+
+/// Longest a queued record waits to be written to the file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One captured request and its response
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureRecord {
+    /// Unix time in milliseconds when the request was received
+    pub timestamp_ms: u64,
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    /// Name of the virtual key the request was made with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Model sent upstream, after resolving any alias
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub upstream: String,
+    pub request_headers: BTreeMap<String, String>,
+    /// The body as JSON if it parses, otherwise as text
+    pub request_body: Value,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub request_body_truncated: bool,
+    pub status: u16,
+    pub streaming: bool,
+    pub response_headers: BTreeMap<String, String>,
+    /// The body as JSON if it parses, otherwise as text. Streams are
+    /// reassembled into a single response.
+    pub response_body: Value,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub response_body_truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub usage_estimated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost_usd: Option<f64>,
+    pub timings: Timings,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Timings {
+    /// Time until the upstream's response headers arrived
+    pub upstream_latency_ms: u64,
+    /// Time until the first chunk of a streamed response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_to_first_chunk_ms: Option<u64>,
+    /// Time until the response (or stream) finished
+    pub total_ms: u64,
+}
+
+/// Whether a request is captured, going by its path and model and the
+/// sample rate
+pub fn should_capture(config: &CaptureConfig, path: &str, model: Option<&str>) -> bool {
+    if !config.paths.is_empty() && !config.paths.iter().any(|pattern| pattern.is_match(path)) {
+        return false;
+    }
+    if !config.models.is_empty() && !model.is_some_and(|model| config.models.iter().any(|p| p.is_match(model))) {
+        return false;
+    }
+    config.sample_rate >= 1.0 || rand::random::<f64>() < config.sample_rate
+}
+
+/// Header values by lowercase name, with credentials and `redact_headers`
+/// replaced. Repeated headers are joined with commas.
+pub fn redact_headers(config: &CaptureConfig, headers: &HeaderMap) -> BTreeMap<String, String> {
+    let mut redacted: BTreeMap<String, String> = BTreeMap::new();
+    for (name, value) in headers {
+        let name = name.as_str();
+        let value = if CREDENTIAL_HEADERS.contains(&name)
+            || config.redact_headers.iter().any(|h| h.eq_ignore_ascii_case(name))
+        {
+            REDACTED.to_string()
+        } else {
+            String::from_utf8_lossy(value.as_bytes()).into_owned()
+        };
+        redacted
+            .entry(name.to_string())
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(&value);
+            })
+            .or_insert(value);
+    }
+    redacted
+}
+
+/// A query string with the values of credential parameters replaced
+fn redact_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if CREDENTIAL_PARAMS.iter().any(|p| p.eq_ignore_ascii_case(name)) => {
+                format!("{}={}", name, REDACTED)
+            }
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// A request being captured, filled in as its response arrives
+pub struct PendingCapture {
+    record: CaptureRecord,
+    max_body_bytes: usize,
+    started: Instant,
+    /// Response bytes seen so far, up to `max_body_bytes`
+    body: Vec<u8>,
+    /// Decodes a streamed response into `assembler`
+    decoder: sse::Decoder,
+    /// Dropped once the reassembled response grows past `max_body_bytes`
+    assembler: Option<StreamAssembler>,
+}
+
+impl PendingCapture {
+    /// Capture the request as the client sent it
+    pub fn new(
+        config: &CaptureConfig,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        body: &[u8],
+        timestamp_ms: u64,
+        started: Instant,
+    ) -> Self {
+        let (request_body, request_body_truncated) = body_value(body, config.max_body_bytes);
+        Self {
+            record: CaptureRecord {
+                timestamp_ms,
+                request_id: String::new(),
+                method: method.to_string(),
+                path: uri.path().to_string(),
+                query: uri.query().map(redact_query),
+                key: None,
+                model: None,
+                upstream: String::new(),
+                request_headers: redact_headers(config, headers),
+                request_body,
+                request_body_truncated,
+                status: 0,
+                streaming: false,
+                response_headers: BTreeMap::new(),
+                response_body: Value::Null,
+                response_body_truncated: false,
+                usage: None,
+                usage_estimated: false,
+                cost_usd: None,
+                timings: Timings::default(),
+            },
+            max_body_bytes: config.max_body_bytes,
+            started,
+            body: vec![],
+            decoder: sse::Decoder::default(),
+            assembler: Some(StreamAssembler::default()),
+        }
+    }
+
+    /// Record who made the request and for which model
+    pub fn describe(&mut self, request_id: &str, key: Option<&str>, model: Option<&str>) {
+        let record = &mut self.record;
+        record.request_id = request_id.to_string();
+        record.key = key.map(str::to_string);
+        record.model = model.map(str::to_string);
+    }
+
+    /// Record where the response came from, and its status and headers. The
+    /// upstream is empty for requests the proxy answered itself.
+    pub fn response(
+        &mut self,
+        config: &CaptureConfig,
+        upstream: &str,
+        status: u16,
+        headers: &HeaderMap,
+        streaming: bool,
+        upstream_latency: Duration,
+    ) {
+        let record = &mut self.record;
+        record.upstream = upstream.to_string();
+        record.status = status;
+        record.response_headers = redact_headers(config, headers);
+        record.streaming = streaming;
+        record.timings.upstream_latency_ms = upstream_latency.as_millis() as u64;
+    }
+
+    /// Add a chunk of the response body
+    pub fn feed(&mut self, chunk: &[u8]) {
+        if self.record.streaming {
+            if self.record.timings.time_to_first_chunk_ms.is_none() {
+                self.record.timings.time_to_first_chunk_ms = Some(self.started.elapsed().as_millis() as u64);
+            }
+            for event in self.decoder.feed(chunk) {
+                if let Some(assembler) = &mut self.assembler {
+                    assembler.push(&event.data);
+                    if assembler.appended > self.max_body_bytes {
+                        self.assembler = None;
+                    }
+                }
+            }
+        }
+        let remaining = (self.max_body_bytes + 1).saturating_sub(self.body.len());
+        self.body.extend_from_slice(&chunk[..chunk.len().min(remaining)]);
+    }
+
+    /// The finished record, with the usage the response reported (or that
+    /// was estimated for it)
+    pub fn finish(mut self, usage: Option<(Usage, Option<f64>)>) -> CaptureRecord {
+        let assembled = match &mut self.assembler {
+            Some(assembler) if self.record.streaming => assembler.finish(),
+            _ => None,
+        };
+        let (response_body, truncated) = match assembled {
+            Some(message) if serde_json::to_vec(&message).map_or(0, |json| json.len()) <= self.max_body_bytes => {
+                (message, false)
+            }
+            _ => {
+                let (body, truncated) = body_value(&self.body, self.max_body_bytes);
+                // A stream too large to reassemble is cut off, even if its raw body fits
+                (body, truncated || self.assembler.is_none())
+            }
+        };
+        let record = &mut self.record;
+        record.response_body = response_body;
+        record.response_body_truncated = truncated;
+        if let Some((usage, cost_usd)) = usage {
+            record.usage_estimated = usage.estimated;
+            record.usage = Some(usage);
+            record.cost_usd = cost_usd;
+        }
+        record.timings.total_ms = self.started.elapsed().as_millis() as u64;
+        self.record
+    }
+}
+
+/// A body as JSON if it's complete and parses, otherwise as (possibly
+/// truncated) text, and whether it was truncated
+fn body_value(body: &[u8], max_bytes: usize) -> (Value, bool) {
+    if body.is_empty() {
+        return (Value::Null, false);
+    }
+    if body.len() > max_bytes {
+        let text = String::from_utf8_lossy(&body[..max_bytes]).into_owned();
+        return (Value::String(text), true);
+    }
+    let value = serde_json::from_slice(body)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into_owned()));
+    (value, false)
+}
+
+/// Rebuilds the response a stream adds up to: the final `response` of a
+/// Responses API stream, or a chat or text completion merged from its chunks
+#[derive(Default)]
+struct StreamAssembler {
+    /// Top-level fields of the chunks (`id`, `model`, `usage`, ...)
+    fields: Map<String, Value>,
+    /// Choices by index
+    choices: BTreeMap<u64, Map<String, Value>>,
+    /// From the Responses API's `response.completed` (or `failed`/`incomplete`) event
+    response: Option<Value>,
+    /// Bytes of text appended and responses kept so far, roughly the size
+    /// of the reassembled response
+    appended: usize,
+}
+
+impl StreamAssembler {
+    fn push(&mut self, data: &str) {
+        let Ok(Value::Object(chunk)) = serde_json::from_str::<Value>(data) else {
+            return;
+        };
+        if let Some(event_type) = chunk.get("type").and_then(Value::as_str) {
+            if matches!(event_type, "response.completed" | "response.failed" | "response.incomplete")
+                && let Some(response) = chunk.get("response")
+            {
+                self.response = Some(response.clone());
+                self.appended += data.len();
+            }
+            return;
+        }
+
+        for (name, value) in chunk {
+            match name.as_str() {
+                "choices" => {
+                    for choice in value.as_array().into_iter().flatten() {
+                        self.push_choice(choice);
+                    }
+                }
+                "object" => {
+                    let object = match value.as_str() {
+                        Some("chat.completion.chunk") => Value::from("chat.completion"),
+                        _ => value,
+                    };
+                    self.fields.insert(name, object);
+                }
+                // Sent in the last chunk only
+                "usage" if value.is_null() => {}
+                "usage" => {
+                    self.fields.insert(name, value);
+                }
+                _ => {
+                    self.fields.entry(name).or_insert(value);
+                }
+            }
+        }
+    }
+
+    fn push_choice(&mut self, choice: &Value) {
+        let index = choice.get("index").and_then(Value::as_u64).unwrap_or(0);
+        let merged = self.choices.entry(index).or_default();
+        merged.insert("index".to_string(), index.into());
+
+        // Chat completions stream deltas of a message
+        if let Some(Value::Object(delta)) = choice.get("delta") {
+            let message = merged
+                .entry("message")
+                .or_insert_with(|| Value::Object(Map::new()))
+                .as_object_mut()
+                .expect("message is always an object");
+            for (name, value) in delta {
+                match (name.as_str(), value) {
+                    (_, Value::Null) => {}
+                    ("content" | "reasoning_content" | "refusal", Value::String(text)) => {
+                        self.appended += append(message, name, text);
+                    }
+                    ("tool_calls", Value::Array(calls)) => {
+                        let merged_calls = message
+                            .entry("tool_calls")
+                            .or_insert_with(|| Value::Array(vec![]))
+                            .as_array_mut()
+                            .expect("tool_calls is always an array");
+                        for call in calls {
+                            self.appended += push_tool_call(merged_calls, call);
+                        }
+                    }
+                    _ => {
+                        message.insert(name.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        // Text completions stream pieces of `text`
+        if let Some(text) = choice.get("text").and_then(Value::as_str) {
+            self.appended += append(merged, "text", text);
+        }
+        for name in ["finish_reason", "logprobs"] {
+            if let Some(value) = choice.get(name).filter(|value| !value.is_null()) {
+                merged.insert(name.to_string(), value.clone());
+            }
+        }
+    }
+
+    /// The reassembled response, if the stream contained one
+    fn finish(&mut self) -> Option<Value> {
+        if let Some(response) = self.response.take() {
+            return Some(response);
+        }
+        if self.fields.is_empty() && self.choices.is_empty() {
+            return None;
+        }
+        let mut message = std::mem::take(&mut self.fields);
+        let choices = std::mem::take(&mut self.choices).into_values().map(Value::Object).collect();
+        message.insert("choices".to_string(), Value::Array(choices));
+        Some(Value::Object(message))
+    }
+}
+
+/// Merge a streamed tool call fragment into the call with the same index.
+/// Returns the number of bytes of text appended.
+fn push_tool_call(calls: &mut Vec<Value>, fragment: &Value) -> usize {
+    let index = fragment.get("index").and_then(Value::as_u64).unwrap_or(0);
+    let position = calls
+        .iter()
+        .position(|call| call.get("index").and_then(Value::as_u64) == Some(index))
+        .unwrap_or_else(|| {
+            calls.push(Value::Object(Map::from_iter([("index".to_string(), index.into())])));
+            calls.len() - 1
+        });
+    let Some(call) = calls[position].as_object_mut() else {
+        return 0;
+    };
+    let Some(fragment) = fragment.as_object() else {
+        return 0;
+    };
+    let mut appended = 0;
+    for (name, value) in fragment {
+        match (name.as_str(), value) {
+            ("index", _) | (_, Value::Null) => {}
+            ("function", Value::Object(function)) => {
+                let merged = call
+                    .entry("function")
+                    .or_insert_with(|| Value::Object(Map::new()))
+                    .as_object_mut()
+                    .expect("function is always an object");
+                for (name, value) in function {
+                    match value {
+                        Value::String(text) => appended += append(merged, name, text),
+                        _ => {
+                            merged.insert(name.clone(), value.clone());
+                        }
+                    }
+                }
+            }
+            _ => {
+                call.insert(name.clone(), value.clone());
+            }
+        }
+    }
+    appended
+}
+
+/// Append text to a string field, creating it if needed. Returns the number
+/// of bytes appended.
+fn append(object: &mut Map<String, Value>, name: &str, text: &str) -> usize {
+    match object.get_mut(name) {
+        Some(Value::String(existing)) => existing.push_str(text),
+        _ => {
+            object.insert(name.to_string(), Value::from(text));
+        }
+    }
+    text.len()
+}
+
+/// Appends capture records to the capture file on a background thread, so
+/// requests never wait on the disk
+pub struct CaptureLog {
+    writer: BackgroundWriter<CaptureRecord>,
+}
+
+impl CaptureLog {
+    /// Open (creating if needed) the capture file and start the writer
+    pub fn open(path: &Path, config: &CaptureConfig) -> io::Result<Self> {
+        let file = CaptureFile::open(path, config.max_file_bytes, config.rotate_daily)?;
+        let writer = BackgroundWriter::spawn("capture", file, config.queue_size, BATCH_SIZE, FLUSH_INTERVAL);
+        Ok(Self { writer })
+    }
+
+    /// Queue a record to be written. Never blocks; drops the record if the
+    /// queue is full.
+    pub fn record(&self, record: CaptureRecord) {
+        self.writer.record(record);
+    }
+
+    /// Block until every queued record has been written
+    pub fn flush(&self) {
+        self.writer.flush();
+    }
+}
+
+/// Days since the Unix epoch, in UTC
+fn today() -> u64 {
+    unix_days(SystemTime::now())
+}
+
+fn unix_days(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default() / SECS_PER_DAY
+}
+
+/// The capture file, rotated to `<path>.<YYYY-MM-DD>.<n>` when it would grow
+/// past `max_bytes` or, with `rotate_daily`, once its day is over
+struct CaptureFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    /// Bytes in the file, including any still buffered
+    size: u64,
+    /// Day (since the epoch) the file's records are from
+    day: u64,
+    max_bytes: u64,
+    rotate_daily: bool,
+}
+
+impl CaptureFile {
+    fn open(path: &Path, max_bytes: u64, rotate_daily: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+            size: metadata.len(),
+            day: metadata.modified().map(unix_days).unwrap_or_else(|_| today()),
+            max_bytes,
+            rotate_daily,
+        })
+    }
+
+    fn write(&mut self, line: &[u8], today: u64) -> io::Result<()> {
+        if self.size > 0
+            && ((self.rotate_daily && self.day != today) || self.size + line.len() as u64 > self.max_bytes)
+        {
+            self.rotate(today)?;
+        }
+        if self.size == 0 {
+            self.day = today;
+        }
+        self.writer.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            log::error!("Failed to write capture file {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Move the current file aside and start a new one
+    fn rotate(&mut self, today: u64) -> io::Result<()> {
+        self.writer.flush()?;
+        let (year, month, day) = dates::civil_from_days(self.day);
+        let rotated = (1..)
+            .map(|n| PathBuf::from(format!("{}.{:04}-{:02}-{:02}.{}", self.path.display(), year, month, day, n)))
+            .find(|rotated| !rotated.exists())
+            .expect("there is always an unused suffix");
+        std::fs::rename(&self.path, &rotated)?;
+        log::info!("Rotated capture file to {}", rotated.display());
+
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        self.size = 0;
+        self.day = today;
+        Ok(())
+    }
+}
+
+impl BatchWrite<CaptureRecord> for CaptureFile {
+    fn write_batch(&mut self, batch: &[CaptureRecord]) {
+        let today = today();
+        for record in batch {
+            let mut line = match serde_json::to_vec(record) {
+                Ok(line) => line,
+                Err(e) => {
+                    log::error!("Failed to serialize capture record {}: {}", record.request_id, e);
+                    continue;
+                }
+            };
+            line.push(b'\n');
+            if let Err(e) = self.write(&line, today) {
+                log::error!("Failed to write capture record {}: {}", record.request_id, e);
+            }
+        }
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pattern::Pattern;
+
+    fn assemble(events: &[&str]) -> Option<Value> {
+        let mut assembler = StreamAssembler::default();
+        for event in events {
+            assembler.push(event);
+        }
+        assembler.finish()
+    }
+
+    #[test]
+    fn test_assemble_chat_stream() {
+        let message = assemble(&[
+            r#"{"id":"c1","object":"chat.completion.chunk","created":1,"model":"gpt-4o","choices":[{"index":0,"delta":{"role":"assistant","content":""},"finish_reason":null}],"usage":null}"#,
+            r#"{"id":"c1","object":"chat.completion.chunk","created":1,"model":"gpt-4o","choices":[{"index":0,"delta":{"content":"Hel"},"finish_reason":null}],"usage":null}"#,
+            r#"{"id":"c1","object":"chat.completion.chunk","created":1,"model":"gpt-4o","choices":[{"index":0,"delta":{"content":"lo"},"finish_reason":"stop"}],"usage":null}"#,
+            r#"{"id":"c1","object":"chat.completion.chunk","created":1,"model":"gpt-4o","choices":[],"usage":{"prompt_tokens":5,"completion_tokens":2,"total_tokens":7}}"#,
+        ]);
+        assert_eq!(
+            message.unwrap(),
+            serde_json::json!({
+                "id": "c1",
+                "object": "chat.completion",
+                "created": 1,
+                "model": "gpt-4o",
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": "Hello"},
+                    "finish_reason": "stop",
+                }],
+                "usage": {"prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7},
+            })
+        );
+    }
+
+    #[test]
+    fn test_assemble_tool_calls() {
+        let message = assemble(&[
+            r#"{"id":"c2","choices":[{"index":0,"delta":{"role":"assistant","tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"get_weather","arguments":""}}]}}]}"#,
+            r#"{"id":"c2","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"city\":"}}]}}]}"#,
+            r#"{"id":"c2","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"Paris\"}"}}]}}]}"#,
+            r#"{"id":"c2","choices":[{"index":0,"delta":{},"finish_reason":"tool_calls"}]}"#,
+        ])
+        .unwrap();
+        let call = &message["choices"][0]["message"]["tool_calls"][0];
+        assert_eq!(call["id"], "call_1");
+        assert_eq!(call["function"]["name"], "get_weather");
+        assert_eq!(call["function"]["arguments"], r#"{"city":"Paris"}"#);
+        assert_eq!(message["choices"][0]["finish_reason"], "tool_calls");
+    }
+
+    #[test]
+    fn test_assemble_responses_stream() {
+        let message = assemble(&[
+            r#"{"type":"response.created","response":{"id":"r1","status":"in_progress"}}"#,
+            r#"{"type":"response.output_text.delta","delta":"Hi"}"#,
+            r#"{"type":"response.completed","response":{"id":"r1","status":"completed","output":[]}}"#,
+        ]);
+        assert_eq!(message.unwrap()["status"], "completed");
+        assert!(assemble(&["not json"]).is_none());
+    }
+
+    #[test]
+    fn test_redact_headers() {
+        let config = CaptureConfig {
+            redact_headers: vec!["X-Internal-Token".to_string()],
+            ..CaptureConfig::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer sk-secret".parse().unwrap());
+        headers.insert("x-internal-token", "abc".parse().unwrap());
+        headers.insert("content-type", "application/json".parse().unwrap());
+        headers.append("accept", "text/plain".parse().unwrap());
+        headers.append("accept", "application/json".parse().unwrap());
+
+        let redacted = redact_headers(&config, &headers);
+        assert_eq!(redacted["authorization"], REDACTED);
+        assert_eq!(redacted["x-internal-token"], REDACTED);
+        assert_eq!(redacted["content-type"], "application/json");
+        assert_eq!(redacted["accept"], "text/plain, application/json");
+    }
+
+    #[test]
+    fn test_redact_query() {
+        assert_eq!(redact_query("api-version=2024-06-01&api-key=secret"), "api-version=2024-06-01&api-key=***");
+        assert_eq!(redact_query("KEY=secret&alt=sse"), "KEY=***&alt=sse");
+        assert_eq!(redact_query("limit=10&key"), "limit=10&key");
+    }
+
+    #[test]
+    fn test_should_capture() {
+        let mut config = CaptureConfig {
+            paths: vec![Pattern::glob("/v1/chat/*").unwrap()],
+            models: vec![Pattern::glob("gpt-4*").unwrap()],
+            ..CaptureConfig::default()
+        };
+        assert!(should_capture(&config, "/v1/chat/completions", Some("gpt-4o")));
+        assert!(!should_capture(&config, "/v1/embeddings", Some("gpt-4o")));
+        assert!(!should_capture(&config, "/v1/chat/completions", Some("llama-3")));
+        assert!(!should_capture(&config, "/v1/chat/completions", None));
+
+        config.sample_rate = 0.0;
+        assert!(!should_capture(&config, "/v1/chat/completions", Some("gpt-4o")));
+    }
+
+    #[test]
+    fn test_pending_capture_truncates_body() {
+        let config = CaptureConfig {
+            max_body_bytes: 8,
+            ..CaptureConfig::default()
+        };
+        let uri: Uri = "/v1/chat/completions".parse().unwrap();
+        let body = br#"{"model":"gpt-4o"}"#;
+        let mut pending = PendingCapture::new(&config, &Method::POST, &uri, &HeaderMap::new(), body, 0, Instant::now());
+        pending.response(&config, "default", 200, &HeaderMap::new(), false, Duration::ZERO);
+        pending.feed(b"0123456789");
+        let record = pending.finish(None);
+        assert_eq!(record.request_body, Value::from(r#"{"model""#));
+        assert!(record.request_body_truncated);
+        assert_eq!(record.response_body, Value::from("01234567"));
+        assert!(record.response_body_truncated);
+    }
+
+    #[test]
+    fn test_pending_capture_stops_assembling_large_streams() {
+        let config = CaptureConfig {
+            max_body_bytes: 200,
+            ..CaptureConfig::default()
+        };
+        let uri: Uri = "/v1/chat/completions".parse().unwrap();
+        let capture = |chunks: usize| {
+            let headers = HeaderMap::new();
+            let mut pending = PendingCapture::new(&config, &Method::POST, &uri, &headers, b"", 0, Instant::now());
+            pending.response(&config, "default", 200, &HeaderMap::new(), true, Duration::ZERO);
+            for _ in 0..chunks {
+                pending.feed(b"data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"0123456789\"}}]}\n\n");
+            }
+            pending.finish(None)
+        };
+
+        let record = capture(2);
+        assert_eq!(record.response_body["choices"][0]["message"]["content"], "01234567890123456789");
+        assert!(!record.response_body_truncated);
+
+        let record = capture(100);
+        assert!(record.response_body.is_string());
+        assert!(record.response_body_truncated);
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("lm-proxy-capture-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("capture.jsonl");
+        let day = dates::days_from_civil(2025, 3, 14).unwrap();
+
+        let mut file = CaptureFile::open(&path, 10, true).unwrap();
+        file.write(b"12345\n", day).unwrap();
+        file.write(b"12345\n", day).unwrap(); // Past max_bytes
+        file.write(b"12345\n", day + 1).unwrap(); // Next day
+        file.flush();
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("capture.jsonl.2025-03-14.1"), "12345\n");
+        assert_eq!(read("capture.jsonl.2025-03-14.2"), "12345\n");
+        assert_eq!(read("capture.jsonl"), "12345\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+use crate::config::CircuitBreakerConfig;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Tracks an upstream's recent failures and decides whether requests may be
+/// sent to it. Closed lets everything through, open fails fast, and half-open
+/// lets a limited number of probe requests through to test recovery.
+#[derive(Default)]
+pub struct CircuitBreaker {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    state: State,
+    consecutive_failures: u32,
+    /// Outcomes (true = success) within the error rate window
+    outcomes: VecDeque<(Instant, bool)>,
+    probes_in_flight: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum State {
+    #[default]
+    Closed,
+    Open {
+        until: Instant,
+    },
+    HalfOpen,
+}
+
+/// Returned when the breaker is rejecting requests
+#[derive(Debug)]
+pub struct CircuitOpen;
+
+/// A state change worth reporting
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transition {
+    Opened,
+    Closed,
+}
+
+/// Point-in-time view of a breaker for the admin endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct BreakerStatus {
+    pub state: &'static str,
+    pub consecutive_failures: u32,
+    /// Seconds until probes are allowed, while open
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_in_secs: Option<u64>,
+}
+
+impl CircuitBreaker {
+    /// Ask to send a request. Returns whether the request is a half-open probe,
+    /// in which case its outcome decides whether the breaker closes.
+    pub fn acquire(&self, config: &CircuitBreakerConfig) -> Result<bool, CircuitOpen> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            State::Closed => Ok(false),
+            State::Open { until } if Instant::now() < until => Err(CircuitOpen),
+            State::Open { .. } => {
+                inner.state = State::HalfOpen;
+                inner.probes_in_flight = 1;
+                Ok(true)
+            }
+            State::HalfOpen if inner.probes_in_flight < config.half_open_probes => {
+                inner.probes_in_flight += 1;
+                Ok(true)
+            }
+            State::HalfOpen => Err(CircuitOpen),
+        }
+    }
+
+    /// Record the outcome of a request that was let through
+    pub fn record(&self, config: &CircuitBreakerConfig, success: bool, probe: bool) -> Option<Transition> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        if probe {
+            inner.probes_in_flight = inner.probes_in_flight.saturating_sub(1);
+        }
+
+        if inner.state == State::HalfOpen {
+            // Only probe outcomes decide recovery; stragglers from before the
+            // breaker opened are ignored
+            if !probe {
+                return None;
+            }
+            return if success {
+                inner.state = State::Closed;
+                inner.consecutive_failures = 0;
+                inner.outcomes.clear();
+                Some(Transition::Closed)
+            } else {
+                inner.open(config, now);
+                Some(Transition::Opened)
+            };
+        }
+
+        if matches!(inner.state, State::Open { .. }) {
+            return None;
+        }
+
+        let window = Duration::from_secs(config.window_secs);
+        inner.outcomes.push_back((now, success));
+        while inner
+            .outcomes
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > window)
+        {
+            inner.outcomes.pop_front();
+        }
+
+        if success {
+            inner.consecutive_failures = 0;
+            return None;
+        }
+
+        inner.consecutive_failures += 1;
+        let too_many_in_a_row = inner.consecutive_failures >= config.consecutive_failures;
+        let error_rate_exceeded = config.error_rate.is_some_and(|threshold| {
+            let total = inner.outcomes.len();
+            let failures = inner.outcomes.iter().filter(|(_, ok)| !ok).count();
+            total >= config.min_requests as usize && failures as f64 / total as f64 >= threshold
+        });
+
+        if too_many_in_a_row || error_rate_exceeded {
+            inner.open(config, now);
+            Some(Transition::Opened)
+        } else {
+            None
+        }
+    }
+
+    /// Give back a probe slot for a request whose outcome was never recorded
+    pub fn release_probe(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.probes_in_flight = inner.probes_in_flight.saturating_sub(1);
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        let inner = self.inner.lock().unwrap();
+        let (state, retry_in_secs) = match inner.state {
+            State::Closed => ("closed", None),
+            State::Open { until } => (
+                "open",
+                Some(until.saturating_duration_since(Instant::now()).as_secs()),
+            ),
+            State::HalfOpen => ("half_open", None),
+        };
+        BreakerStatus {
+            state,
+            consecutive_failures: inner.consecutive_failures,
+            retry_in_secs,
+        }
+    }
+}
+
+impl Inner {
+    fn open(&mut self, config: &CircuitBreakerConfig, now: Instant) {
+        self.state = State::Open {
+            until: now + Duration::from_secs(config.open_secs),
+        };
+        self.probes_in_flight = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            consecutive_failures: 3,
+            open_secs: 60,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::default();
+        let config = config();
+
+        assert_eq!(breaker.record(&config, false, false), None);
+        assert_eq!(breaker.record(&config, true, false), None);
+        assert_eq!(breaker.record(&config, false, false), None);
+        assert_eq!(breaker.record(&config, false, false), None);
+        assert_eq!(breaker.record(&config, false, false), Some(Transition::Opened));
+
+        assert!(breaker.acquire(&config).is_err());
+        assert_eq!(breaker.status().state, "open");
+    }
+
+    #[test]
+    fn test_opens_on_error_rate() {
+        let breaker = CircuitBreaker::default();
+        let config = CircuitBreakerConfig {
+            consecutive_failures: 100,
+            error_rate: Some(0.5),
+            min_requests: 4,
+            ..config()
+        };
+
+        assert_eq!(breaker.record(&config, false, false), None);
+        assert_eq!(breaker.record(&config, true, false), None);
+        assert_eq!(breaker.record(&config, true, false), None);
+        assert_eq!(breaker.record(&config, false, false), Some(Transition::Opened));
+    }
+
+    #[test]
+    fn test_half_open_probes() {
+        let breaker = CircuitBreaker::default();
+        let config = CircuitBreakerConfig {
+            consecutive_failures: 1,
+            open_secs: 0,
+            half_open_probes: 2,
+            ..config()
+        };
+
+        assert_eq!(breaker.record(&config, false, false), Some(Transition::Opened));
+
+        // The open period has passed, so a limited number of probes get through
+        assert!(breaker.acquire(&config).unwrap());
+        assert!(breaker.acquire(&config).unwrap());
+        assert!(breaker.acquire(&config).is_err());
+        assert_eq!(breaker.status().state, "half_open");
+
+        // A failed probe reopens the breaker
+        assert_eq!(breaker.record(&config, false, true), Some(Transition::Opened));
+
+        assert!(breaker.acquire(&config).unwrap());
+        breaker.release_probe();
+        assert!(breaker.acquire(&config).unwrap());
+        assert_eq!(breaker.record(&config, true, true), Some(Transition::Closed));
+        assert!(!breaker.acquire(&config).unwrap());
+        assert_eq!(breaker.status().state, "closed");
+    }
+}
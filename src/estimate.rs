@@ -0,0 +1,158 @@
+//! Token counts estimated with a bundled BPE tokenizer, for upstreams that
+//! don't report usage
+
+use crate::models::Usage;
+use serde_json::Value;
+use tiktoken_rs::CoreBPE;
+
+/// Tokens OpenAI adds around each chat message, and to prime the reply
+const TOKENS_PER_MESSAGE: u32 = 3;
+const REPLY_PRIMING_TOKENS: u32 = 3;
+
+/// Estimate usage from the request body and the response text. The
+/// tokenizer is picked by model family; unknown models use `cl100k_base`.
+pub fn estimate(model: Option<&str>, request_body: &[u8], completion: &str) -> Usage {
+    let bpe = tokenizer(model);
+    let prompt_tokens = serde_json::from_slice::<Value>(request_body)
+        .map(|body| prompt_tokens(bpe, &body))
+        .unwrap_or(0);
+    let completion_tokens = count(bpe, completion);
+
+    Usage {
+        prompt_tokens: Some(prompt_tokens),
+        completion_tokens: Some(completion_tokens),
+        total_tokens: Some(prompt_tokens + completion_tokens),
+        estimated: true,
+        ..Default::default()
+    }
+}
+
+/// The generated text in a non-streaming response body: chat message
+/// content, completion text, or Responses API output text
+pub fn response_text(body: &[u8]) -> String {
+    let Ok(body) = serde_json::from_slice::<Value>(body) else {
+        return String::new();
+    };
+    let mut text = String::new();
+    for choice in body["choices"].as_array().into_iter().flatten() {
+        collect_text(&choice["message"]["content"], &mut text);
+        collect_text(&choice["text"], &mut text);
+    }
+    for item in body["output"].as_array().into_iter().flatten() {
+        collect_text(&item["content"], &mut text);
+    }
+    text
+}
+
+/// The generated text in one streamed event: a chat delta, completion
+/// text, or a Responses API `response.output_text.delta`
+pub fn chunk_text(data: &str) -> Option<String> {
+    let chunk = serde_json::from_str::<Value>(data).ok()?;
+    if chunk["type"] == "response.output_text.delta" {
+        return chunk["delta"].as_str().map(str::to_string);
+    }
+    let mut text = String::new();
+    for choice in chunk["choices"].as_array().into_iter().flatten() {
+        collect_text(&choice["delta"]["content"], &mut text);
+        collect_text(&choice["text"], &mut text);
+    }
+    (!text.is_empty()).then_some(text)
+}
+
+fn tokenizer(model: Option<&str>) -> &'static CoreBPE {
+    model
+        .and_then(|model| tiktoken_rs::bpe_for_model(model).ok())
+        .unwrap_or_else(tiktoken_rs::cl100k_base_singleton)
+}
+
+fn count(bpe: &CoreBPE, text: &str) -> u32 {
+    bpe.count_ordinary(text) as u32
+}
+
+/// Tokens in chat `messages`, a completion `prompt`, or embeddings or
+/// Responses API `input`
+fn prompt_tokens(bpe: &CoreBPE, body: &Value) -> u32 {
+    if let Some(messages) = body["messages"].as_array() {
+        let content: u32 = messages
+            .iter()
+            .map(|message| {
+                let mut text = String::new();
+                collect_text(&message["content"], &mut text);
+                count(bpe, &text)
+            })
+            .sum();
+        return content + TOKENS_PER_MESSAGE * messages.len() as u32 + REPLY_PRIMING_TOKENS;
+    }
+    let mut text = String::new();
+    collect_text(&body["prompt"], &mut text);
+    collect_text(&body["input"], &mut text);
+    count(bpe, &text)
+}
+
+/// Append the text in a content value: a string, an array of strings, or
+/// an array of parts or messages with `text` or `content`
+fn collect_text(value: &Value, text: &mut String) {
+    match value {
+        Value::String(s) => text.push_str(s),
+        Value::Array(items) => {
+            for item in items {
+                collect_text(item, text);
+            }
+        }
+        Value::Object(object) => {
+            if let Some(part) = object.get("text") {
+                collect_text(part, text);
+            } else if let Some(content) = object.get("content") {
+                collect_text(content, text);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_chat_completion() {
+        let request = br#"{"model": "gpt-4o", "messages": [
+            {"role": "system", "content": "You are terse."},
+            {"role": "user", "content": [{"type": "text", "text": "Say hello world"}]}
+        ]}"#;
+        let response = br#"{"id": "1", "choices": [{"index": 0, "message": {"role": "assistant", "content": "hello world"}}]}"#;
+
+        let usage = estimate(Some("gpt-4o"), request, &response_text(response));
+        // "You are terse." is 4 tokens and "Say hello world" 3, plus message overhead
+        assert_eq!(usage.prompt_tokens, Some(4 + 3 + 2 * 3 + 3));
+        assert_eq!(usage.completion_tokens, Some(2));
+        assert_eq!(usage.total_tokens, Some(18));
+        assert!(usage.estimated);
+    }
+
+    #[test]
+    fn test_estimate_other_shapes() {
+        let usage = estimate(Some("llama-3-8b"), br#"{"prompt": ["hello world", " again"]}"#, "");
+        assert_eq!(usage.prompt_tokens, Some(3));
+        assert_eq!(usage.completion_tokens, Some(0));
+
+        let usage = estimate(None, br#"{"input": [{"role": "user", "content": "hello world"}]}"#, "");
+        assert_eq!(usage.prompt_tokens, Some(2));
+
+        let response = br#"{"id": "resp_1", "output": [{"type": "message", "content": [{"type": "output_text", "text": "Hi!"}]}]}"#;
+        assert_eq!(response_text(response), "Hi!");
+    }
+
+    #[test]
+    fn test_chunk_text() {
+        let chunk = r#"{"id": "c1", "choices": [{"index": 0, "delta": {"content": "Hel"}}]}"#;
+        assert_eq!(chunk_text(chunk).as_deref(), Some("Hel"));
+        let chunk = r#"{"id": "c1", "choices": [{"index": 0, "text": "lo"}]}"#;
+        assert_eq!(chunk_text(chunk).as_deref(), Some("lo"));
+        let event = r#"{"type": "response.output_text.delta", "item_id": "msg_1", "delta": "Hi"}"#;
+        assert_eq!(chunk_text(event).as_deref(), Some("Hi"));
+
+        let chunk = r#"{"id": "c1", "choices": [{"index": 0, "delta": {"role": "assistant"}}]}"#;
+        assert!(chunk_text(chunk).is_none());
+    }
+}
@@ -1,28 +1,422 @@
-use crate::{config::Config, models};
+use crate::{
+    auth,
+    budget::{self, BudgetTracker},
+    capture::{self, CaptureLog, PendingCapture},
+    circuit_breaker::Transition,
+    config::Config,
+    estimate,
+    ledger::{Ledger, UsageRecord},
+    metrics::{self, MetricEvent, MetricsQueue, MetricsSink},
+    models, pricing,
+    prometheus::{self, Labels, Metrics},
+    retry, routing, sse,
+    trace::{self, ConnectTiming, RequestTrace, Span, SpanKind, TRACEPARENT_HEADER, Tracer},
+    upstream::{SelectedMember, UpstreamRegistry, UpstreamStatus},
+};
 use axum::{
     body::Body,
     response::Response,
     http::{self, HeaderName},
 };
-use futures_util::StreamExt;
+use bytes::Bytes;
+use futures_util::{StreamExt, stream::BoxStream};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Response header naming the upstream that served the request
+const UPSTREAM_HEADER: &str = "x-lm-proxy-upstream";
+
+/// Request header with comma-separated labels recorded in the ledger. It's
+/// not forwarded upstream.
+const TAGS_HEADER: &str = "x-lm-proxy-tags";
+
+/// Response header with the id the request is recorded under. Clients can
+/// choose the id by sending `x-request-id`.
+const REQUEST_ID_HEADER: &str = "x-lm-proxy-request-id";
+
+/// Response header with the request's cost in USD, when `cost_header` is set
+const COST_HEADER: &str = "x-lm-proxy-cost";
+
+/// Per-request details carried along for usage reporting
+struct RequestState {
+    /// Name of the upstream the request was forwarded to
+    upstream: String,
+    /// Pool member that served the request. Held until the response body is
+    /// done so it counts as outstanding for load balancing.
+    member: SelectedMember,
+    /// Model sent upstream, after resolving any alias
+    model: Option<String>,
+    /// Alias the client requested, if it used one
+    alias: Option<String>,
+    /// Model name to write into responses in place of the upstream's
+    response_model: Option<String>,
+    /// Name of the virtual key the client authenticated with
+    key: Option<String>,
+    team: Option<String>,
+    /// Config the request started with
+    active: Arc<ActiveConfig>,
+    budgets: Arc<BudgetTracker>,
+    request_id: String,
+    tags: Vec<String>,
+    path: String,
+    status: u16,
+    streaming: bool,
+    tracking_usage: bool,
+    /// Unix time in milliseconds when the request was received
+    received_at_ms: u64,
+    started: Instant,
+    /// Usage reported by the upstream, once seen, and its cost
+    usage: Mutex<Option<(models::Usage, Option<f64>)>>,
+    ledger: Option<Arc<Ledger>>,
+    /// Request body to estimate usage from if the upstream doesn't report it.
+    /// Only kept when `estimate_usage` is on.
+    estimate_from: Option<Bytes>,
+    /// Text streamed so far, for estimating usage
+    completion_text: Mutex<String>,
+    metrics: Arc<Metrics>,
+    metrics_queue: Arc<MetricsQueue>,
+    trace: RequestTrace,
+    /// Span of the upstream call, ended with the response
+    upstream_span: Mutex<Option<Span>>,
+    /// When the upstream's response headers arrived
+    headers_at: SystemTime,
+    /// Where the request is captured to, if it was picked for capture
+    capture: Option<(Arc<CaptureLog>, Mutex<PendingCapture>)>,
+}
+
+/// Holds a request's state until its response is finished (or the client
+/// goes away), which is when the context is dropped
+struct RequestContext(Option<RequestState>);
+
+impl std::ops::Deref for RequestContext {
+    type Target = RequestState;
+
+    fn deref(&self) -> &RequestState {
+        self.0.as_ref().expect("the state is only taken when dropped")
+    }
+}
+
+impl Drop for RequestContext {
+    fn drop(&mut self) {
+        let Some(state) = self.0.take() else {
+            return;
+        };
+        // Counting tokens for an estimate is too slow to do on the runtime
+        if state.needs_estimate()
+            && let Ok(runtime) = tokio::runtime::Handle::try_current()
+        {
+            runtime.spawn_blocking(move || state.finish());
+        } else {
+            state.finish();
+        }
+    }
+}
+
+impl RequestState {
+    /// Whether usage has to be estimated because the upstream never reported it
+    fn needs_estimate(&self) -> bool {
+        self.tracking_usage && self.estimate_from.is_some() && self.usage.lock().unwrap().is_none()
+    }
+
+    /// Write the request to the ledger and the capture file, and end its
+    /// upstream span
+    fn finish(mut self) {
+        // A stream that ended without reporting usage
+        if self.tracking_usage && self.usage.lock().unwrap().is_none() {
+            let completion = std::mem::take(&mut *self.completion_text.lock().unwrap());
+            if let Some(usage) = self.estimate_usage(&completion) {
+                self.record_usage(&usage);
+            }
+        }
+        self.finish_upstream_span();
+        if let Some((log, pending)) = self.capture.take() {
+            let usage = self.usage.lock().unwrap().clone();
+            log.record(pending.into_inner().unwrap().finish(usage));
+        }
+
+        if !self.tracking_usage {
+            return;
+        }
+
+        let Some(ledger) = &self.ledger else {
+            return;
+        };
+        let (usage, cost_usd) = self.usage.lock().unwrap().take().unwrap_or_default();
+        ledger.record(UsageRecord {
+            timestamp_ms: self.received_at_ms,
+            request_id: self.request_id.clone(),
+            key: self.key.clone(),
+            team: self.team.clone(),
+            tags: self.tags.clone(),
+            model: self.model.clone(),
+            upstream: self.upstream.clone(),
+            path: self.path.clone(),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.total_tokens,
+            cached_tokens: usage.cached_tokens(),
+            cost_usd,
+            estimated: usage.estimated,
+            latency_ms: self.started.elapsed().as_millis() as u64,
+            status: self.status,
+            streaming: self.streaming,
+        });
+    }
+}
+
+impl RequestState {
+    /// Log a request's usage, report it to the metrics endpoint and count it
+    /// against its key's budgets. Returns the cost, if the model has a price.
+    fn record_usage(&self, usage: &models::Usage) -> Option<f64> {
+        let cost_usd = self
+            .model
+            .as_deref()
+            .and_then(|model| pricing::cost_usd(&self.active.config.prices, model, usage, self.received_at_ms / 1000));
+        self.log_usage(usage, cost_usd);
+        *self.usage.lock().unwrap() = Some((usage.clone(), cost_usd));
+        self.metrics.record_tokens(&self.labels(), usage);
+
+        if let Some(total_tokens) = usage.total_tokens {
+            let event = MetricEvent {
+                model: self.model.clone(),
+                key: self.key.clone(),
+                team: self.team.clone(),
+                path: Some(prometheus::path_label(&self.path).to_string()),
+                upstream: Some(self.upstream.clone()),
+                prompt_tokens: usage.prompt_tokens,
+                completion_tokens: usage.completion_tokens,
+                total_tokens: Some(total_tokens),
+                cached_tokens: usage.cached_tokens(),
+                reasoning_tokens: usage.reasoning_tokens(),
+                prompt_audio_tokens: usage.prompt_audio_tokens(),
+                completion_audio_tokens: usage.completion_audio_tokens(),
+                latency_ms: Some(self.started.elapsed().as_millis() as u64),
+                cost_usd,
+                estimated: usage.estimated,
+                ..MetricEvent::new("token-count", total_tokens)
+            };
+            self.metrics_queue.push(&self.active, event);
+        }
+
+        let Some(key) = &self.key else {
+            return cost_usd;
+        };
+        let tokens = usage
+            .total_tokens
+            .or_else(|| Some(usage.prompt_tokens? + usage.completion_tokens.unwrap_or(0)))
+            .unwrap_or(0);
+        let budgets = &self.active.config.budgets;
+        for alert in self
+            .budgets
+            .record(&budgets.limits, key, self.team.as_deref(), tokens.into(), cost_usd.unwrap_or(0.0), unix_now())
+        {
+            log::warn!("Budget soft limit reached: {}", alert);
+            let event = MetricEvent {
+                key: Some(key.clone()),
+                team: self.team.clone(),
+                ..MetricEvent::new("budget-soft-limit", 1)
+            };
+            self.metrics_queue.push(&self.active, event);
+            if let Some(url) = &budgets.alert_url {
+                budget::send_alert(self.active.client.clone(), url.clone(), alert);
+            }
+        }
+        cost_usd
+    }
+
+    /// End the upstream span with the usage it reported, after a span for
+    /// the rest of the stream
+    fn finish_upstream_span(&self) {
+        let Some(mut span) = self.upstream_span.lock().unwrap().take() else {
+            return;
+        };
+        if let Some((usage, _)) = &*self.usage.lock().unwrap() {
+            if let Some(tokens) = usage.prompt_tokens {
+                span.set("gen_ai.usage.input_tokens", tokens);
+            }
+            if let Some(tokens) = usage.completion_tokens {
+                span.set("gen_ai.usage.output_tokens", tokens);
+            }
+        }
+        if self.streaming {
+            let mut completion = span.child("stream completion", SpanKind::Internal);
+            completion.start = self.headers_at;
+            self.trace.finish(completion, None);
+        }
+        self.trace.finish(span, None);
+    }
+
+    /// Add a chunk of the response body to the capture, if the request is captured
+    fn capture_chunk(&self, chunk: &[u8]) {
+        if let Some((_, pending)) = &self.capture {
+            pending.lock().unwrap().feed(chunk);
+        }
+    }
+
+    fn labels(&self) -> Labels {
+        Labels::new(self.model.as_deref(), &self.path, &self.upstream, self.status, self.streaming)
+    }
+
+    fn estimate_usage(&self, completion: &str) -> Option<models::Usage> {
+        estimate_usage(self.model.as_deref(), self.estimate_from.as_deref(), self.status, completion)
+    }
+
+    fn log_usage(&self, usage: &models::Usage, cost_usd: Option<f64>) {
+        let mut fields = String::new();
+        if let Some(key) = &self.key {
+            fields.push_str(&format!("key={} ", key));
+        }
+        if let Some(team) = &self.team {
+            fields.push_str(&format!("team={} ", team));
+        }
+        fields.push_str(&format!("upstream={}", self.upstream));
+        if self.member.is_pooled {
+            fields.push_str(&format!(" member={}", self.member.name));
+        }
+        if let Some(model) = &self.model {
+            fields.push_str(&format!(" model={}", model));
+        }
+        if let Some(alias) = &self.alias {
+            fields.push_str(&format!(" alias={}", alias));
+        }
+        let mut usage = usage.log_format();
+        if let Some(cost) = cost_usd {
+            usage.push_str(&format!(" cost_usd={:.6}", cost));
+        }
+        log::info!("[USAGE] {} {}", fields, usage);
+    }
+}
+
+/// A config along with the client built from it. Requests hold on to the
+/// snapshot they started with so a reload never changes an in-flight request.
+pub struct ActiveConfig {
+    pub config: Config,
+    pub client: reqwest::Client,
+    /// Incremented every time the config is reloaded, starting at 1
+    pub version: u64,
+    /// Unix timestamp (seconds) of when this config became active
+    pub loaded_at: u64,
+    /// Where metric events are sent
+    pub metrics_sinks: Vec<Arc<dyn MetricsSink>>,
+}
 
-/// Payload for posting metrics to external endpoint
-#[derive(serde::Serialize)]
-struct MetricsPayload {
-    name: String,
-    value: u32,
+impl ActiveConfig {
+    pub fn new(config: Config, client: reqwest::Client, version: u64) -> Self {
+        Self {
+            metrics_sinks: metrics::sinks(&config, &client),
+            config,
+            client,
+            version,
+            loaded_at: unix_now(),
+        }
+    }
 }
 
 /// Proxy service that forwards requests to upstream API
 #[derive(Clone)]
 pub struct ProxyService {
-    client: reqwest::Client,
-    config: Config,
+    active: Arc<RwLock<Arc<ActiveConfig>>>,
+    upstreams: Arc<UpstreamRegistry>,
+    budgets: Arc<BudgetTracker>,
+    ledger: Option<Arc<Ledger>>,
+    capture: Option<Arc<CaptureLog>>,
+    metrics: Arc<Metrics>,
+    metrics_queue: Arc<MetricsQueue>,
+    tracer: Arc<Tracer>,
 }
 
 impl ProxyService {
     pub fn new(client: reqwest::Client, config: Config) -> Self {
-        Self { client, config }
+        let active = ActiveConfig::new(config, client, 1);
+        let metrics = Arc::new(Metrics::default());
+        Self {
+            active: Arc::new(RwLock::new(Arc::new(active))),
+            upstreams: Arc::new(UpstreamRegistry::default()),
+            budgets: Arc::new(BudgetTracker::default()),
+            ledger: None,
+            capture: None,
+            metrics_queue: Arc::new(MetricsQueue::new(metrics.clone())),
+            tracer: Arc::new(Tracer::new(metrics.clone())),
+            metrics,
+        }
+    }
+
+    /// Count usage against budgets loaded from (and saved to) a state file
+    pub fn with_budgets(mut self, budgets: BudgetTracker) -> Self {
+        self.budgets = Arc::new(budgets);
+        self
+    }
+
+    /// Record every usage-tracked request in the ledger
+    pub fn with_ledger(mut self, ledger: Ledger) -> Self {
+        self.ledger = Some(Arc::new(ledger));
+        self
+    }
+
+    pub fn ledger(&self) -> Option<&Ledger> {
+        self.ledger.as_deref()
+    }
+
+    /// Capture requests matching `capture`'s filters to the capture file
+    pub fn with_capture(mut self, capture: CaptureLog) -> Self {
+        self.capture = Some(Arc::new(capture));
+        self
+    }
+
+    pub fn capture(&self) -> Option<&CaptureLog> {
+        self.capture.as_deref()
+    }
+
+    /// Returns the config currently used for new requests
+    pub fn active_config(&self) -> Arc<ActiveConfig> {
+        self.active.read().unwrap().clone()
+    }
+
+    /// Token usage counted against budgets
+    pub fn budgets(&self) -> &BudgetTracker {
+        &self.budgets
+    }
+
+    /// Request metrics served at `/metrics`
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Events waiting to be sent to `metrics_url`
+    pub fn metrics_queue(&self) -> &MetricsQueue {
+        &self.metrics_queue
+    }
+
+    /// Spans waiting to be exported
+    pub fn tracer(&self) -> &Tracer {
+        &self.tracer
+    }
+
+    /// Runtime state (breakers, member pools) for each upstream
+    pub fn upstreams(&self) -> &UpstreamRegistry {
+        &self.upstreams
+    }
+
+    /// Current status of every upstream in the active config
+    pub fn upstream_statuses(&self) -> Vec<UpstreamStatus> {
+        let active = self.active_config();
+        active
+            .config
+            .all_upstreams()
+            .iter()
+            .map(|upstream| self.upstreams.status(upstream))
+            .collect()
+    }
+
+    /// Atomically swap in a new config for subsequent requests. Requests
+    /// (including open streams) that already started keep their original config.
+    /// Returns the new config version.
+    pub fn reload(&self, config: Config) -> reqwest::Result<u64> {
+        let client = config.http_client()?;
+        let mut active = self.active.write().unwrap();
+        let version = active.version + 1;
+        *active = Arc::new(ActiveConfig::new(config, client, version));
+        Ok(version)
     }
 
     /// Forward a request to upstream and track usage if applicable
@@ -33,120 +427,623 @@ impl ProxyService {
         headers: http::HeaderMap<http::HeaderValue>,
         body_bytes: Vec<u8>,
     ) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
+        let trace = self.start_trace(&method, &uri, &headers);
+        self.forward_traced_request(method, uri, headers, body_bytes, trace).await
+    }
+
+    /// Start a request's server span, continuing the trace in its
+    /// `traceparent` header. Work done before forwarding (e.g. reading the
+    /// body) can be traced under it.
+    pub fn start_trace(&self, method: &http::Method, uri: &http::Uri, headers: &http::HeaderMap) -> RequestTrace {
+        let parent = headers.get(TRACEPARENT_HEADER).and_then(|v| v.to_str().ok());
+        RequestTrace::start(self.active_config(), self.tracer.clone(), method.as_str(), uri.path(), parent)
+    }
+
+    /// Like `forward_request`, recording spans under `trace`
+    pub async fn forward_traced_request(
+        &self,
+        method: http::Method,
+        uri: http::Uri,
+        headers: http::HeaderMap<http::HeaderValue>,
+        body_bytes: Vec<u8>,
+        trace: RequestTrace,
+    ) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
+        let result = self.forward(method, uri, headers, body_bytes, &trace).await;
+        match &result {
+            Ok(response) => trace.set_status(response.status().as_u16()),
+            // Answered with a 502 by the server
+            Err(_) => trace.set_status(http::StatusCode::BAD_GATEWAY.as_u16()),
+        }
+        result
+    }
+
+    async fn forward(
+        &self,
+        method: http::Method,
+        uri: http::Uri,
+        headers: http::HeaderMap<http::HeaderValue>,
+        body_bytes: Vec<u8>,
+        trace: &RequestTrace,
+    ) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
+        let started = Instant::now();
+        let received_at_ms = unix_now_ms();
+        let active = self.active_config();
         let path = uri.path().to_string();
         let query = uri.query().map(|q| format!("?{}", q)).unwrap_or_default();
-        let full_path = format!("{}{}", path, query);
+        let host = headers
+            .get(http::header::HOST)
+            .and_then(|v| v.to_str().ok())
+            .or_else(|| uri.authority().map(|a| a.as_str()));
+
+        let authenticated = auth::authenticate(&active.config.virtual_keys, &headers);
+        let request_id = headers
+            .get("x-request-id")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+            .unwrap_or_else(new_request_id);
+        let mut body_bytes = body_bytes;
+        let mut model = models::try_parse_request_model(&body_bytes);
+
+        // Captured as the client sent it, before any rewriting, so requests
+        // the proxy rejects itself are captured too
+        let capture = self
+            .capture
+            .clone()
+            .filter(|_| capture::should_capture(&active.config.capture, &path, model.as_deref()))
+            .map(|log| {
+                let mut pending = PendingCapture::new(
+                    &active.config.capture,
+                    &method,
+                    &uri,
+                    &headers,
+                    &body_bytes,
+                    received_at_ms,
+                    started,
+                );
+                let key = authenticated.as_ref().ok().copied().flatten().map(|k| k.name.as_str());
+                pending.describe(&request_id, key, model.as_deref());
+                (log, pending)
+            });
+
+        let virtual_key = match authenticated {
+            Ok(virtual_key) => virtual_key,
+            Err(e) => {
+                let response = error_response(
+                    http::StatusCode::UNAUTHORIZED,
+                    &e.to_string(),
+                    "invalid_request_error",
+                    e.code(),
+                );
+                return Ok(self.reject(capture, &path, "", response).await);
+            }
+        };
+
+        if let Some(virtual_key) = virtual_key
+            && let Err(e) = self.budgets.check(
+                &active.config.budgets.limits,
+                &virtual_key.name,
+                virtual_key.team.as_deref(),
+                unix_now(),
+            )
+        {
+            log::warn!("Rejecting request for key {}: {}", virtual_key.name, e);
+            let response = error_response(
+                http::StatusCode::TOO_MANY_REQUESTS,
+                &format!("You exceeded your current quota: {}.", e),
+                "insufficient_quota",
+                Some("insufficient_quota"),
+            );
+            return Ok(self.reject(capture, &path, "", response).await);
+        }
 
         let tracking_usage = models::is_usage_tracked_path(&path);
-        let upstream_url = self.config.upstream_url_for_path(&full_path);
+        let tags: Vec<String> = headers
+            .get_all(TAGS_HEADER)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_string)
+            .collect();
 
-        let filtered_headers = filter_hop_by_hop_headers(headers);
-        let upstream_response = self.send_upstream_request(method, &upstream_url, filtered_headers, body_bytes).await?;
+        // Swap an aliased model for the concrete one before routing and forwarding
+        let mut alias = None;
+        if let Some(resolved) = model.as_deref().and_then(|m| active.config.resolve_model_alias(m))
+            && let Some(rewritten) = models::rewrite_model(&body_bytes, resolved)
+        {
+            body_bytes = rewritten;
+            alias = model.replace(resolved.to_string());
+        }
 
-        let status = upstream_response.status();
-        let mut builder = http::Response::builder().status(status);
+        // Ask for streaming usage on the client's behalf, and hide the extra
+        // usage chunk from clients that didn't ask for it
+        let mut strip_usage_chunk = false;
+        if active.config.inject_stream_usage
+            && path.ends_with("/completions")
+            && let Some(rewritten) = models::include_stream_usage(&body_bytes)
+        {
+            body_bytes = rewritten;
+            strip_usage_chunk = true;
+        }
 
-        for (name, value) in upstream_response.headers() {
-            if !is_hop_by_hop_header(name) {
-                builder = builder.header(name, value);
+        let route = match routing::resolve(&active.config, &method, host, &path, &query, model.as_deref()) {
+            Ok(route) => route,
+            Err(e @ routing::RoutingError::ModelNotFound(_)) => {
+                let response = error_response(
+                    http::StatusCode::NOT_FOUND,
+                    &e.to_string(),
+                    "invalid_request_error",
+                    Some("model_not_found"),
+                );
+                return Ok(self.reject(capture, &path, "", response).await);
             }
+        };
+        let mut filtered_headers = filter_hop_by_hop_headers(headers);
+        filtered_headers.remove(TAGS_HEADER);
+        if virtual_key.is_some() {
+            // The upstream's own credential is injected below
+            auth::strip_credentials(&mut filtered_headers);
         }
+        let body_bytes = Bytes::from(body_bytes);
+
+        // Try the routed upstream, then each fallback in order, until one
+        // responds with a status that doesn't trigger failover
+        let mut targets = route.targets().into_iter().peekable();
+        let (target, member, upstream_response, upstream_latency, upstream_span) = loop {
+            let target = targets.next().expect("a route always has at least one upstream");
+            let is_last = targets.peek().is_none();
+
+            let body = match target.model.as_deref() {
+                Some(fallback_model) => models::rewrite_model(&body_bytes, fallback_model)
+                    .map(Bytes::from)
+                    .unwrap_or_else(|| body_bytes.clone()),
+                None => body_bytes.clone(),
+            };
+            let member = match self.upstreams.select(&target.upstream) {
+                Ok(member) => member,
+                Err(reason) if is_last => {
+                    log::warn!("Upstream {} unavailable: {}", target.upstream.name, reason);
+                    let response = error_response(
+                        http::StatusCode::SERVICE_UNAVAILABLE,
+                        &format!("Upstream {} is temporarily unavailable", target.upstream.name),
+                        "server_error",
+                        Some("upstream_unavailable"),
+                    );
+                    return Ok(self.reject(capture, &path, &target.upstream.name, response).await);
+                }
+                Err(reason) => {
+                    log::warn!("Upstream {} unavailable ({}), failing over", target.upstream.name, reason);
+                    self.push_metric(&active, MetricEvent::for_upstream("upstream-fallback", &target.upstream.name));
+                    continue;
+                }
+            };
+            if member.is_pooled {
+                log::debug!("Selected member {} of upstream {}", member.name, member.upstream);
+            }
+            let mut headers = filtered_headers.clone();
+            member.apply_credentials(&mut headers);
+
+            let upstream_url = member.url_for_path(&route.path);
+            let request_model = target.model.as_deref().or(model.as_deref());
+            let mut upstream_span = start_upstream_span(trace, &method, &path, request_model, &upstream_url);
+            if let Some(span) = &mut upstream_span {
+                span.set("lm_proxy.upstream", target.upstream.name.as_str());
+                if let Some(traceparent) = trace.traceparent(span)
+                    && let Ok(value) = http::HeaderValue::from_str(&traceparent)
+                {
+                    headers.insert(TRACEPARENT_HEADER, value);
+                }
+            }
+            let connect = ConnectTiming::default();
+            let sent = Instant::now();
+            let sent_at = SystemTime::now();
+            let result = connect
+                .scope(self.send_upstream_request(&active, method.clone(), &upstream_url, headers, body, tracking_usage))
+                .await;
+            let upstream_latency = sent.elapsed();
+            if let Some(span) = &mut upstream_span {
+                record_upstream_response(trace, span, &connect, sent_at, &result);
+            }
+            let transition = match &result {
+                Ok(response) => member.record_status(response.status()),
+                Err(_) => member.record_error(),
+            };
+            match transition {
+                Some(Transition::Opened) => {
+                    log::warn!("Circuit opened for upstream {}", target.upstream.name);
+                    self.push_metric(&active, MetricEvent::for_upstream("circuit-opened", &target.upstream.name));
+                }
+                Some(Transition::Closed) => {
+                    log::info!("Circuit closed for upstream {}", target.upstream.name);
+                    self.push_metric(&active, MetricEvent::for_upstream("circuit-closed", &target.upstream.name));
+                }
+                None => {}
+            }
 
+            match result {
+                Ok(response)
+                    if is_last || !active.config.failover.statuses.contains(&response.status().as_u16()) =>
+                {
+                    break (target, member, response, upstream_latency, upstream_span);
+                }
+                Ok(response) => log::warn!(
+                    "Upstream {} responded with {}, failing over",
+                    target.upstream.name,
+                    response.status()
+                ),
+                Err(e) if is_last => {
+                    // Answered with a 502 by the server
+                    let status = http::StatusCode::BAD_GATEWAY;
+                    self.record_rejection(&path, &target.upstream.name, status);
+                    if let Some((log, pending)) = capture {
+                        let headers = http::HeaderMap::new();
+                        capture_rejection(&active, log, pending, &target.upstream.name, status, &headers, &[]);
+                    }
+                    if let Some(span) = upstream_span {
+                        trace.finish(span, None);
+                    }
+                    return Err(e);
+                }
+                Err(e) => log::warn!("Upstream {} failed, failing over: {}", target.upstream.name, e),
+            }
+            if let Some(span) = upstream_span {
+                trace.finish(span, None);
+            }
+            self.push_metric(&active, MetricEvent::for_upstream("upstream-fallback", &target.upstream.name));
+        };
+
+        let status = upstream_response.status();
         let content_type = upstream_response
             .headers()
             .get(http::header::CONTENT_TYPE)
             .and_then(|v| v.to_str().ok());
         let is_streaming = content_type.is_some_and(|ct| ct.contains("text/event-stream"));
 
+        let requested_model = alias.clone().or_else(|| model.clone());
+        let model = target.model.or(model);
+        let ctx = RequestContext(Some(RequestState {
+            upstream: target.upstream.name,
+            member,
+            response_model: requested_model
+                .filter(|requested| active.config.rewrite_response_model && Some(requested) != model.as_ref()),
+            model,
+            alias,
+            key: virtual_key.map(|k| k.name.clone()),
+            team: virtual_key.and_then(|k| k.team.clone()),
+            active: active.clone(),
+            budgets: self.budgets.clone(),
+            request_id,
+            tags,
+            path,
+            status: status.as_u16(),
+            streaming: is_streaming,
+            tracking_usage,
+            received_at_ms,
+            started,
+            usage: Mutex::new(None),
+            ledger: self.ledger.clone(),
+            estimate_from: (tracking_usage && active.config.estimate_usage).then(|| body_bytes.clone()),
+            completion_text: Mutex::new(String::new()),
+            metrics: self.metrics.clone(),
+            metrics_queue: self.metrics_queue.clone(),
+            trace: trace.clone(),
+            upstream_span: Mutex::new(upstream_span),
+            headers_at: SystemTime::now(),
+            capture: capture.map(|(log, pending)| (log, Mutex::new(pending))),
+        }));
+        if let Some((_, pending)) = &ctx.capture {
+            let mut pending = pending.lock().unwrap();
+            pending.describe(&ctx.request_id, ctx.key.as_deref(), ctx.model.as_deref());
+            pending.response(
+                &active.config.capture,
+                &ctx.upstream,
+                ctx.status,
+                upstream_response.headers(),
+                is_streaming,
+                upstream_latency,
+            );
+        }
+        let labels = ctx.labels();
+        ctx.metrics.record_request(&labels, Some(upstream_latency));
+        let event = MetricEvent::for_request(&labels, ctx.key.as_deref(), ctx.team.as_deref(), Some(upstream_latency));
+        self.push_metric(&active, event);
+
+        let mut builder = http::Response::builder()
+            .status(status)
+            .header(UPSTREAM_HEADER, ctx.upstream.as_str())
+            .header(REQUEST_ID_HEADER, ctx.request_id.as_str());
+
+        for (name, value) in upstream_response.headers() {
+            if !is_hop_by_hop_header(name) {
+                builder = builder.header(name, value);
+            }
+        }
+
         if is_streaming {
-            self.handle_streaming_response(upstream_response, builder, tracking_usage)
+            self.handle_streaming_response(ctx, upstream_response, builder, tracking_usage, strip_usage_chunk)
         } else if tracking_usage {
-            self.handle_non_streaming_tracked_response(upstream_response, builder).await
+            self.handle_non_streaming_tracked_response(&ctx, upstream_response, builder).await
         } else {
-            self.handle_passthrough_response(upstream_response, builder)
+            self.handle_passthrough_response(ctx, upstream_response, builder)
         }
     }
 
     async fn send_upstream_request(
         &self,
+        active: &ActiveConfig,
         method: http::Method,
         url: &str,
-        headers: http::HeaderMap<http::HeaderValue>,
-        body_bytes: Vec<u8>,
+        mut headers: http::HeaderMap<http::HeaderValue>,
+        body_bytes: Bytes,
+        is_llm_request: bool,
     ) -> Result<reqwest::Response, Box<dyn std::error::Error + Send + Sync>> {
-        let mut request = self.client.request(method, url);
+        // Skip the Host and Content-Length headers so reqwest sets them
+        // correctly from the URL and (possibly rewritten) body
+        headers.remove(http::header::HOST);
+        headers.remove(http::header::CONTENT_LENGTH);
+
+        let policy = &active.config.retry;
+        let retryable = retry::is_retryable_request(policy, &method, is_llm_request);
+        let mut attempt = 1;
 
-        // Apply headers but skip the Host header so reqwest sets it correctly from the URL
-        for (name, value) in headers {
-            if let Some(name) = name && name != http::header::HOST {
-                request = request.header(name, value);
+        loop {
+            let mut request = active.client.request(method.clone(), url).headers(headers.clone());
+            if !body_bytes.is_empty() {
+                request = request.body(body_bytes.clone());
             }
-        }
 
-        if !body_bytes.is_empty() {
-            request = request.body(body_bytes);
-        }
+            let result = request.send().await;
+            if !retryable || attempt >= policy.max_attempts {
+                return Ok(result?);
+            }
+
+            let (delay, reason) = match &result {
+                Ok(response) if policy.statuses.contains(&response.status().as_u16()) => (
+                    retry::response_delay(policy, attempt, response.status(), response.headers()),
+                    response.status().to_string(),
+                ),
+                Err(e) if retry::is_retryable_error(policy, e) => {
+                    let delay =
+                        retry::backoff_delay(policy.initial_backoff_ms, policy.max_backoff_ms, policy.jitter, attempt);
+                    (Some(delay), e.to_string())
+                }
+                _ => return Ok(result?),
+            };
+            let Some(delay) = delay else {
+                return Ok(result?);
+            };
 
-        Ok(request.send().await?)
+            log::warn!(
+                "Retrying {} in {:?} after {} (attempt {} of {})",
+                url,
+                delay,
+                reason,
+                attempt + 1,
+                policy.max_attempts
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
     }
 
     async fn handle_non_streaming_tracked_response(
         &self,
+        ctx: &RequestContext,
         upstream_response: reqwest::Response,
-        builder: http::response::Builder,
+        mut builder: http::response::Builder,
     ) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
-        let body_bytes = upstream_response.bytes().await?;
+        let mut body_bytes = upstream_response.bytes().await?;
 
-        if let Some(usage) = models::try_parse_usage_from_body(&body_bytes) {
-            log::info!("[USAGE] {}", usage.log_format());
-            if let Some(total_tokens) = usage.total_tokens {
-                self.post_metrics_if_configured(total_tokens);
+        let mut usage = models::try_parse_usage_from_body(&body_bytes);
+        if usage.is_none() && ctx.estimate_from.is_some() {
+            // Counting tokens is too slow to do on the runtime
+            let (model, request_body, status) = (ctx.model.clone(), ctx.estimate_from.clone(), ctx.status);
+            let body = body_bytes.clone();
+            usage = tokio::task::spawn_blocking(move || {
+                let completion = estimate::response_text(&body);
+                estimate_usage(model.as_deref(), request_body.as_deref(), status, &completion)
+            })
+            .await?;
+        }
+        if let Some(usage) = usage
+            && let Some(cost) = ctx.record_usage(&usage)
+            && ctx.active.config.cost_header
+        {
+            builder = builder.header(COST_HEADER, format!("{:.6}", cost));
+        }
+
+        if let Some(model) = &ctx.response_model
+            && let Some(rewritten) = models::rewrite_model(&body_bytes, model)
+        {
+            body_bytes = Bytes::from(rewritten);
+            if let Some(headers) = builder.headers_mut() {
+                headers.remove(http::header::CONTENT_LENGTH);
             }
         }
+        ctx.capture_chunk(&body_bytes);
 
         Ok(builder.body(Body::from(body_bytes)).unwrap())
     }
 
     fn handle_streaming_response(
         &self,
+        ctx: RequestContext,
         upstream_response: reqwest::Response,
-        builder: http::response::Builder,
+        mut builder: http::response::Builder,
         tracking_usage: bool,
+        strip_usage_chunk: bool,
     ) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
-        let client = self.client.clone();
-        let metrics_url = self.config.metrics_url.clone();
-
-        let upstream_stream = Box::pin(upstream_response.bytes_stream().map(move |result| {
-            if tracking_usage
-                && let Ok(chunk) = &result
-                && let Some(usage) = parse_usage_from_sse_chunk(chunk)
-            {
-                log::info!("[USAGE] {}", usage.log_format());
-                if let Some(total_tokens) = usage.total_tokens {
-                    post_metrics_async(client.clone(), metrics_url.clone(), total_tokens);
+        let mut upstream_stream = upstream_response.bytes_stream().boxed();
+        if let Some(model) = ctx.response_model.clone() {
+            upstream_stream = rewrite_sse_model(upstream_stream, model);
+            if let Some(headers) = builder.headers_mut() {
+                headers.remove(http::header::CONTENT_LENGTH);
+            }
+        }
+
+        // Shared by both stages of the stream, so it's dropped along with it
+        let ctx = Arc::new(ctx);
+        let usage_ctx = ctx.clone();
+        let mut decoder = sse::Decoder::default();
+        let mut first_chunk = true;
+        let mut upstream_stream = Box::pin(upstream_stream.map(move |result| {
+            if first_chunk && result.is_ok() {
+                first_chunk = false;
+                let elapsed = usage_ctx.started.elapsed();
+                usage_ctx.metrics.record_time_to_first_token(&usage_ctx.labels(), elapsed);
+            }
+            if tracking_usage && let Ok(chunk) = &result {
+                for event in decoder.feed(chunk) {
+                    // Skip [DONE] marker
+                    if event.data == "[DONE]" {
+                        continue;
+                    }
+                    if let Some(usage) = models::try_parse_usage_from_chunk(&event.data) {
+                        usage_ctx.record_usage(&usage);
+                    } else if usage_ctx.estimate_from.is_some()
+                        && let Some(text) = estimate::chunk_text(&event.data)
+                    {
+                        usage_ctx.completion_text.lock().unwrap().push_str(&text);
+                    }
                 }
             }
 
-            result.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
-        }));
+            result
+        }))
+        .boxed();
+
+        if strip_usage_chunk {
+            upstream_stream = strip_sse_usage_chunk(upstream_stream);
+            if let Some(headers) = builder.headers_mut() {
+                headers.remove(http::header::CONTENT_LENGTH);
+            }
+        }
+
+        // Captured as the client gets it, without a stripped usage chunk
+        let upstream_stream = upstream_stream.map(move |result| {
+            if let Ok(chunk) = &result {
+                ctx.capture_chunk(chunk);
+            }
+            result
+        });
 
         Ok(builder.body(Body::from_stream(upstream_stream)).unwrap())
     }
 
     fn handle_passthrough_response(
         &self,
+        ctx: RequestContext,
         upstream_response: reqwest::Response,
         builder: http::response::Builder,
     ) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
-        let stream = upstream_response.bytes_stream();
+        // Keep the context alive until the body finishes streaming
+        let stream = upstream_response.bytes_stream().map(move |result| {
+            if let Ok(chunk) = &result {
+                ctx.capture_chunk(chunk);
+            }
+            result
+        });
         Ok(builder.body(Body::from_stream(stream)).unwrap())
     }
 
-    fn post_metrics_if_configured(&self, total_tokens: u32) {
-        if let Some(url) = self.config.metrics_url.clone() {
-            post_metrics_async(self.client.clone(), Some(url), total_tokens);
-        }
+    /// Answer a request that ended without an upstream response to pass on:
+    /// count it, and capture the response the client gets
+    async fn reject(
+        &self,
+        capture: Option<(Arc<CaptureLog>, PendingCapture)>,
+        path: &str,
+        upstream: &str,
+        response: Response,
+    ) -> Response {
+        self.record_rejection(path, upstream, response.status());
+        let Some((log, pending)) = capture else {
+            return response;
+        };
+        let (parts, body) = response.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap_or_default();
+        capture_rejection(&self.active_config(), log, pending, upstream, parts.status, &parts.headers, &body);
+        Response::from_parts(parts, Body::from(body))
+    }
+
+    /// Count a request that ended without an upstream response to pass on.
+    /// The model is left out, since no upstream accepted the client's value.
+    fn record_rejection(&self, path: &str, upstream: &str, status: http::StatusCode) {
+        let labels = Labels::new(None, path, upstream, status.as_u16(), false);
+        self.metrics.record_request(&labels, None);
+        self.push_metric(&self.active_config(), MetricEvent::for_request(&labels, None, None, None));
+    }
+
+    /// Queue an event for `metrics_url`, if one is configured
+    pub(crate) fn push_metric(&self, active: &ActiveConfig, event: MetricEvent) {
+        self.metrics_queue.push(active, event);
+    }
+}
+
+/// Estimate usage from the request and the generated text, if enabled (i.e.
+/// the request body was kept) and the request succeeded
+fn estimate_usage(
+    model: Option<&str>,
+    request_body: Option<&[u8]>,
+    status: u16,
+    completion: &str,
+) -> Option<models::Usage> {
+    let request_body = request_body?;
+    if !(200..300).contains(&status) {
+        return None;
     }
+    Some(estimate::estimate(model, request_body, completion))
+}
+
+/// Capture a response the proxy made itself, e.g. to reject a request
+fn capture_rejection(
+    active: &ActiveConfig,
+    log: Arc<CaptureLog>,
+    mut pending: PendingCapture,
+    upstream: &str,
+    status: http::StatusCode,
+    headers: &http::HeaderMap,
+    body: &[u8],
+) {
+    pending.response(&active.config.capture, upstream, status.as_u16(), headers, false, Duration::ZERO);
+    pending.feed(body);
+    log.record(pending.finish(None));
+}
+
+/// Build an OpenAI-style JSON error response
+pub(crate) fn error_response(status: http::StatusCode, message: &str, error_type: &str, code: Option<&str>) -> Response {
+    let body = models::ErrorResponse {
+        error: models::ErrorDetail {
+            message: message.to_string(),
+            error_type: error_type.to_string(),
+            param: None,
+            code: code.map(str::to_string),
+        },
+    };
+
+    http::Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(&body).unwrap()))
+        .unwrap()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn unix_now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn new_request_id() -> String {
+    format!("req_{:032x}", rand::random::<u128>())
 }
 
 /// Filter out hop-by-hop headers that should not be forwarded
@@ -170,37 +1067,180 @@ fn is_hop_by_hop_header(name: &HeaderName) -> bool {
     )
 }
 
-/// Parse usage from an SSE chunk
-fn parse_usage_from_sse_chunk(chunk: &[u8]) -> Option<models::Usage> {
-    let text = std::str::from_utf8(chunk).ok()?;
-    let text = text.trim().strip_prefix("data: ")?;
+/// Rewrite the `model` in each SSE `data:` line. Chunks are buffered up to the
+/// last complete line so JSON split across network chunks is rewritten whole.
+fn rewrite_sse_model(
+    stream: BoxStream<'static, reqwest::Result<Bytes>>,
+    model: String,
+) -> BoxStream<'static, reqwest::Result<Bytes>> {
+    futures_util::stream::unfold((stream, Vec::new(), false), move |(mut stream, mut buf, done)| {
+        let model = model.clone();
+        async move {
+            if done {
+                return None;
+            }
+            loop {
+                match stream.next().await {
+                    Some(Ok(chunk)) => {
+                        buf.extend_from_slice(&chunk);
+                        let Some(end) = buf.iter().rposition(|b| *b == b'\n') else {
+                            continue;
+                        };
+                        let rest = buf.split_off(end + 1);
+                        let complete = std::mem::replace(&mut buf, rest);
+                        return Some((Ok(rewrite_sse_lines(&complete, &model)), (stream, buf, false)));
+                    }
+                    Some(Err(e)) => return Some((Err(e), (stream, buf, true))),
+                    None if buf.is_empty() => return None,
+                    None => return Some((Ok(rewrite_sse_lines(&buf, &model)), (stream, Vec::new(), true))),
+                }
+            }
+        }
+    })
+    .boxed()
+}
 
-    // Skip [DONE] marker
-    if text == "[DONE]" {
-        return None;
+fn rewrite_sse_lines(data: &[u8], model: &str) -> Bytes {
+    let mut out = Vec::with_capacity(data.len());
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        let content = line.trim_ascii_end();
+        let rewritten = content
+            .strip_prefix(b"data:")
+            .and_then(|json| models::rewrite_model(json.trim_ascii_start(), model));
+
+        match rewritten {
+            Some(json) => {
+                out.extend_from_slice(b"data: ");
+                out.extend_from_slice(&json);
+                out.extend_from_slice(&line[content.len()..]);
+            }
+            None => out.extend_from_slice(line),
+        }
     }
+    Bytes::from(out)
+}
 
-    models::try_parse_usage_from_chunk(text)
+/// Remove the usage-only chunk added by `stream_options.include_usage`.
+/// Chunks are buffered up to the end of the last complete event so an event
+/// split across network chunks is checked whole.
+fn strip_sse_usage_chunk(
+    stream: BoxStream<'static, reqwest::Result<Bytes>>,
+) -> BoxStream<'static, reqwest::Result<Bytes>> {
+    futures_util::stream::unfold((stream, Vec::new(), false), |(mut stream, mut buf, done)| async move {
+        if done {
+            return None;
+        }
+        loop {
+            match stream.next().await {
+                Some(Ok(chunk)) => {
+                    buf.extend_from_slice(&chunk);
+                    let (events, end) = remove_usage_events(&buf);
+                    buf.drain(..end);
+                    if !events.is_empty() {
+                        return Some((Ok(events), (stream, buf, false)));
+                    }
+                }
+                Some(Err(e)) => return Some((Err(e), (stream, buf, true))),
+                None if buf.is_empty() => return None,
+                None => return Some((Ok(Bytes::from(buf)), (stream, Vec::new(), true))),
+            }
+        }
+    })
+    .boxed()
 }
 
-/// Post metrics asynchronously (spawned task, fire-and-forget)
-fn post_metrics_async(client: reqwest::Client, url: Option<String>, total_tokens: u32) {
-    if let Some(url) = url {
-        tokio::spawn(async move {
-            let payload = MetricsPayload {
-                name: "token-count".to_string(),
-                value: total_tokens,
-            };
+/// Copy the complete events in `data`, leaving out usage-only chunks. Returns
+/// them along with the length of `data` they took up.
+fn remove_usage_events(data: &[u8]) -> (Bytes, usize) {
+    let mut out = Vec::with_capacity(data.len());
+    let mut event_start = 0;
+    let mut pos = 0;
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        if !line.ends_with(b"\n") {
+            break;
+        }
+        pos += line.len();
+        // A blank line ends an event
+        if line.trim_ascii().is_empty() {
+            let event = &data[event_start..pos];
+            let mut decoder = sse::Decoder::default();
+            let usage_only = decoder
+                .feed(event)
+                .iter()
+                .any(|event| models::is_usage_only_chunk(&event.data));
+            if !usage_only {
+                out.extend_from_slice(event);
+            }
+            event_start = pos;
+        }
+    }
+    (Bytes::from(out), event_start)
+}
+
+/// Start the client span for one upstream attempt. Model calls are named and
+/// described per the GenAI semantic conventions, other requests by method.
+fn start_upstream_span(
+    trace: &RequestTrace,
+    method: &http::Method,
+    path: &str,
+    model: Option<&str>,
+    url: &str,
+) -> Option<Span> {
+    let operation = trace::gen_ai_operation(path);
+    let name = match (operation, model) {
+        (Some(operation), Some(model)) => format!("{} {}", operation, model),
+        (Some(operation), None) => operation.to_string(),
+        (None, _) => method.to_string(),
+    };
+    let mut span = trace.child(&name, SpanKind::Client)?;
+    if let Some(operation) = operation {
+        span.set("gen_ai.operation.name", operation);
+    }
+    if let Some(model) = model {
+        span.set("gen_ai.request.model", model);
+    }
+    span.set("http.request.method", method.as_str());
+    if let Ok(url) = reqwest::Url::parse(url) {
+        if let Some(host) = url.host_str() {
+            span.set("server.address", host);
+        }
+        if let Some(port) = url.port_or_known_default() {
+            span.set("server.port", port);
+        }
+    }
+    Some(span)
+}
+
+/// Record an upstream attempt's connection and time-to-first-byte spans, and
+/// its outcome on the attempt's span
+fn record_upstream_response(
+    trace: &RequestTrace,
+    span: &mut Span,
+    connect: &ConnectTiming,
+    sent_at: SystemTime,
+    result: &Result<reqwest::Response, Box<dyn std::error::Error + Send + Sync>>,
+) {
+    if let Some((start, end)) = connect.get() {
+        let mut connect = span.child("upstream connect", SpanKind::Internal);
+        connect.start = start;
+        trace.finish(connect, Some(end));
+    }
+    let mut first_byte = span.child("time to first byte", SpanKind::Internal);
+    first_byte.start = sent_at;
+    trace.finish(first_byte, None);
 
-            if let Err(e) = client
-                .post(url)
-                .json(&payload)
-                .send()
-                .await
-                .map(|r| r.error_for_status())
-            {
-                log::warn!("Failed to post metrics: {}", e);
+    match result {
+        Ok(response) => {
+            let status = response.status().as_u16();
+            span.set("http.response.status_code", status);
+            if status >= 400 {
+                span.set("error.type", status.to_string());
+                span.error = Some(format!("responded with {}", status));
             }
-        });
+        }
+        Err(e) => {
+            span.set("error.type", "transport");
+            span.error = Some(e.to_string());
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,76 @@
+use crate::handler::ProxyService;
+use crate::metrics::MetricEvent;
+use crate::upstream::HealthCheck;
+use axum::{extract::State, http::StatusCode, Json};
+use futures_util::future::join_all;
+use std::time::Duration;
+
+/// How often to look for health checks that are due. Each upstream's own
+/// `interval_secs` decides how often its members are actually checked.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Run health checks for every upstream that has them configured, picking up
+/// config reloads as they happen
+pub async fn run(proxy: ProxyService) {
+    let mut ticker = tokio::time::interval(TICK);
+    loop {
+        ticker.tick().await;
+        // Checks mark themselves as started, so a slow check isn't run twice
+        let proxy = proxy.clone();
+        tokio::spawn(async move { check_due(&proxy).await });
+    }
+}
+
+/// Run the health checks that are due and wait for them to finish
+pub async fn check_due(proxy: &ProxyService) {
+    let active = proxy.active_config();
+    let checks: Vec<HealthCheck> = active
+        .config
+        .all_upstreams()
+        .iter()
+        .flat_map(|upstream| proxy.upstreams().due_health_checks(upstream))
+        .collect();
+
+    join_all(checks.into_iter().map(|check| {
+        let active = active.clone();
+        async move {
+            let result = active
+                .client
+                .get(&check.url)
+                .headers(check.headers.clone())
+                .timeout(Duration::from_secs(check.config.timeout_secs))
+                .send()
+                .await
+                .map_err(|e| e.without_url().to_string())
+                .and_then(|response| match response.status() {
+                    status if status.is_success() => Ok(()),
+                    status => Err(format!("responded with {}", status)),
+                });
+
+            match check.record(result) {
+                Some(true) => {
+                    log::info!("Member {} of upstream {} is healthy again", check.member, check.upstream);
+                    proxy.push_metric(&active, MetricEvent::for_upstream("upstream-healthy", &check.upstream));
+                }
+                Some(false) => {
+                    log::warn!("Member {} of upstream {} is unhealthy", check.member, check.upstream);
+                    proxy.push_metric(&active, MetricEvent::for_upstream("upstream-unhealthy", &check.upstream));
+                }
+                None => {}
+            }
+        }
+    }))
+    .await;
+}
+
+/// Returns the health of each upstream, with a 503 when any is unavailable
+pub async fn upstreams_handler(State(proxy): State<ProxyService>) -> (StatusCode, Json<serde_json::Value>) {
+    let upstreams = proxy.upstream_statuses();
+    let healthy = upstreams.iter().all(|upstream| upstream.healthy);
+    let status = if healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    let body = serde_json::json!({
+        "status": if healthy { "ok" } else { "degraded" },
+        "upstreams": upstreams,
+    });
+    (status, Json(body))
+}
@@ -0,0 +1,223 @@
+use crate::config::LedgerConfig;
+use crate::writer::{BackgroundWriter, BatchWrite};
+use rusqlite::{Connection, OpenFlags, params};
+use std::path::Path;
+use std::time::Duration;
+
+/// Schema changes, applied in order. `PRAGMA user_version` records how many
+/// have been applied, so new migrations must only ever be appended.
+const MIGRATIONS: &[&str] = &[r#"
+    CREATE TABLE usage (
+        id INTEGER PRIMARY KEY,
+        timestamp_ms INTEGER NOT NULL,
+        request_id TEXT NOT NULL,
+        key TEXT,
+        model TEXT,
+        upstream TEXT NOT NULL,
+        path TEXT NOT NULL,
+        prompt_tokens INTEGER,
+        completion_tokens INTEGER,
+        total_tokens INTEGER,
+        cached_tokens INTEGER,
+        latency_ms INTEGER NOT NULL,
+        status INTEGER NOT NULL,
+        streaming INTEGER NOT NULL
+    );
+    CREATE INDEX usage_timestamp ON usage (timestamp_ms);
+    CREATE INDEX usage_key ON usage (key, timestamp_ms);
+"#, r#"
+    ALTER TABLE usage ADD COLUMN team TEXT;
+    -- Comma-separated with leading and trailing commas, e.g. `,batch,eval,`
+    ALTER TABLE usage ADD COLUMN tags TEXT;
+"#, r#"
+    ALTER TABLE usage ADD COLUMN cost_usd REAL;
+"#, r#"
+    ALTER TABLE usage ADD COLUMN estimated INTEGER NOT NULL DEFAULT 0;
+"#];
+
+/// One usage-tracked request
+#[derive(Debug, Clone, Default)]
+pub struct UsageRecord {
+    /// Unix time in milliseconds when the request was received
+    pub timestamp_ms: u64,
+    pub request_id: String,
+    /// Name of the virtual key the request was made with
+    pub key: Option<String>,
+    pub team: Option<String>,
+    /// Labels from the request's `x-lm-proxy-tags` header
+    pub tags: Vec<String>,
+    pub model: Option<String>,
+    pub upstream: String,
+    pub path: String,
+    pub prompt_tokens: Option<u32>,
+    pub completion_tokens: Option<u32>,
+    pub total_tokens: Option<u32>,
+    pub cached_tokens: Option<u32>,
+    /// Cost from the price table, if the model has a price
+    pub cost_usd: Option<f64>,
+    /// Token counts were estimated by the proxy
+    pub estimated: bool,
+    /// Time until the response (or stream) finished
+    pub latency_ms: u64,
+    pub status: u16,
+    pub streaming: bool,
+}
+
+/// Writes usage records to SQLite on a background thread, in batches, so
+/// requests never wait on the database
+pub struct Ledger {
+    writer: BackgroundWriter<UsageRecord>,
+}
+
+impl Ledger {
+    /// Open (creating and migrating if needed) the database and start the writer
+    pub fn open(path: &Path, config: &LedgerConfig) -> rusqlite::Result<Self> {
+        let connection = connect(path)?;
+        let flush_interval = Duration::from_millis(config.flush_interval_ms);
+        Ok(Self {
+            writer: BackgroundWriter::spawn("ledger", connection, config.queue_size, config.batch_size, flush_interval),
+        })
+    }
+
+    /// Queue a record to be written. Never blocks; drops the record if the
+    /// queue is full.
+    pub fn record(&self, record: UsageRecord) {
+        self.writer.record(record);
+    }
+
+    /// Block until every queued record has been written
+    pub fn flush(&self) {
+        self.writer.flush();
+    }
+}
+
+/// Open the database and bring its schema up to date
+pub fn connect(path: &Path) -> rusqlite::Result<Connection> {
+    let mut connection = Connection::open(path)?;
+    // WAL lets reports read while the proxy is writing
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    connection.busy_timeout(Duration::from_secs(5))?;
+    migrate(&mut connection)?;
+    Ok(connection)
+}
+
+/// Open the database for reading, e.g. for reports, without touching its
+/// schema or journal mode
+pub fn connect_read_only(path: &Path) -> rusqlite::Result<Connection> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    connection.busy_timeout(Duration::from_secs(5))?;
+    Ok(connection)
+}
+
+/// Whether every migration has been applied
+pub fn is_up_to_date(connection: &Connection) -> rusqlite::Result<bool> {
+    Ok(applied_migrations(connection)? >= MIGRATIONS.len())
+}
+
+fn applied_migrations(connection: &Connection) -> rusqlite::Result<usize> {
+    let applied: i64 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    Ok(applied as usize)
+}
+
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    let applied = applied_migrations(connection)?;
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let tx = connection.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", version as i64 + 1)?;
+        tx.commit()?;
+        log::info!("Applied usage ledger migration {}", version + 1);
+    }
+    Ok(())
+}
+
+impl BatchWrite<UsageRecord> for Connection {
+    fn write_batch(&mut self, batch: &[UsageRecord]) {
+        if let Err(e) = insert(self, batch) {
+            log::error!("Failed to write {} usage records: {}", batch.len(), e);
+        }
+    }
+}
+
+fn insert(connection: &mut Connection, records: &[UsageRecord]) -> rusqlite::Result<()> {
+    let tx = connection.transaction()?;
+    {
+        let mut statement = tx.prepare_cached(
+            "INSERT INTO usage (timestamp_ms, request_id, key, team, tags, model, upstream, path, \
+             prompt_tokens, completion_tokens, total_tokens, cached_tokens, cost_usd, estimated, latency_ms, status, streaming) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)",
+        )?;
+        for record in records {
+            statement.execute(params![
+                record.timestamp_ms as i64,
+                record.request_id,
+                record.key,
+                record.team,
+                (!record.tags.is_empty()).then(|| format!(",{},", record.tags.join(","))),
+                record.model,
+                record.upstream,
+                record.path,
+                record.prompt_tokens,
+                record.completion_tokens,
+                record.total_tokens,
+                record.cached_tokens,
+                record.cost_usd,
+                record.estimated,
+                record.latency_ms as i64,
+                record.status,
+                record.streaming,
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_are_written_in_batches() {
+        let path = std::env::temp_dir().join(format!("lm-proxy-ledger-{}.db", std::process::id()));
+        let config = LedgerConfig {
+            batch_size: 2,
+            flush_interval_ms: 60_000,
+            ..Default::default()
+        };
+        let ledger = Ledger::open(&path, &config).unwrap();
+        let count = || -> i64 {
+            let connection = connect(&path).unwrap();
+            connection.query_row("SELECT COUNT(*) FROM usage", [], |row| row.get(0)).unwrap()
+        };
+
+        let record = UsageRecord {
+            request_id: "req_1".to_string(),
+            upstream: "default".to_string(),
+            path: "/chat/completions".to_string(),
+            total_tokens: Some(30),
+            status: 200,
+            ..Default::default()
+        };
+        ledger.record(record.clone());
+        // A full batch is written without waiting for the flush interval
+        ledger.record(record.clone());
+        ledger.record(record);
+        for _ in 0..100 {
+            if count() >= 2 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(count(), 2);
+
+        ledger.flush();
+        assert_eq!(count(), 3);
+
+        // Reopening an up-to-date database doesn't reapply migrations
+        drop(ledger);
+        connect(&path).unwrap();
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
@@ -1,3 +1,25 @@
+pub mod admin;
+pub mod auth;
+pub mod batch;
+pub mod budget;
+pub mod capture;
+pub mod circuit_breaker;
 pub mod config;
+pub mod dates;
+pub mod estimate;
 pub mod handler;
-pub mod models;
\ No newline at end of file
+pub mod health;
+pub mod ledger;
+pub mod metrics;
+pub mod models;
+pub mod pattern;
+pub mod pricing;
+pub mod prometheus;
+pub mod report;
+pub mod retry;
+pub mod routing;
+pub mod sse;
+pub mod statsd;
+pub mod trace;
+pub mod upstream;
+pub mod writer;
\ No newline at end of file
@@ -1,13 +1,11 @@
-mod config;
-mod handler;
-mod models;
-
-use axum::{routing::any, Router};
+use axum::{routing::{any, get}, Router};
 use axum::extract::State;
 use axum::response::IntoResponse;
 use clap::Parser;
-use config::{Args, Config};
-use handler::ProxyService;
+use lm_proxy::budget::{self, BudgetTracker};
+use lm_proxy::{admin, capture::CaptureLog, health, ledger::Ledger, metrics, prometheus, report, trace};
+use lm_proxy::config::{Args, Command, Config, UsageCommand};
+use lm_proxy::handler::ProxyService;
 use futures_util::StreamExt;
 use tokio::signal::unix::{SignalKind, signal};
 
@@ -18,21 +16,27 @@ async fn proxy_handler(
     let method = req.method().clone();
     let uri = req.uri().clone();
     let headers = std::mem::take(req.headers_mut());
+    let trace = proxy.start_trace(&method, &uri, &headers);
 
     // Collect request body bytes
+    let body_span = trace.child("read request body", trace::SpanKind::Internal);
     let mut body_stream = req.into_body().into_data_stream();
     let mut body_bytes: Vec<u8> = vec![];
     while let Some(chunk_result) = body_stream.next().await {
         match chunk_result {
             Ok(bytes) => body_bytes.extend_from_slice(&bytes),
             Err(e) => {
+                trace.set_status(axum::http::StatusCode::BAD_REQUEST.as_u16());
                 return (axum::http::StatusCode::BAD_REQUEST, format!("Failed to read body: {}", e))
                     .into_response();
             }
         }
     }
+    if let Some(span) = body_span {
+        trace.finish(span, None);
+    }
 
-    match proxy.forward_request(method, uri, headers, body_bytes).await {
+    match proxy.forward_traced_request(method, uri, headers, body_bytes, trace).await {
         Ok(resp) => resp,
         Err(e) => (axum::http::StatusCode::BAD_GATEWAY, format!("Proxy error: {}", e)).into_response(),
     }
@@ -60,12 +64,60 @@ async fn shutdown_signal() {
     }
 }
 
+/// Reload the config from the original CLI args (and config file) on SIGHUP.
+/// Invalid configs are logged and the current config stays active.
+async fn reload_on_sighup(args: Args, proxy: ProxyService) {
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to install signal handler");
+
+    while hangup.recv().await.is_some() {
+        log::info!("Received SIGHUP, reloading config");
+        let config = match args.clone().into_config() {
+            Ok(config) => config,
+            Err(e) => {
+                log::error!("Failed to reload config, keeping current config: {}", e);
+                continue;
+            }
+        };
+
+        let current = proxy.active_config();
+        if config.listen_addr != current.config.listen_addr {
+            log::warn!(
+                "listen_addr changed to {} but requires a restart to take effect",
+                config.listen_addr
+            );
+        }
+        if config.ledger.path != current.config.ledger.path {
+            log::warn!("ledger.path changed but requires a restart to take effect");
+        }
+        if config.capture.path != current.config.capture.path {
+            log::warn!("capture.path changed but requires a restart to take effect");
+        }
+        if config.log_level != current.config.log_level {
+            log::warn!("log_level changed but requires a restart to take effect");
+        }
+        if config.budgets.state_file != current.config.budgets.state_file {
+            log::warn!("budgets.state_file changed but requires a restart to take effect");
+        }
+
+        match proxy.reload(config) {
+            Ok(version) => log::info!("Config reloaded (version {})", version),
+            Err(e) => log::error!("Failed to reload config, keeping current config: {}", e),
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    let config: Config = args.into_config()?;
+    let config: Config = args.clone().into_config()?;
+
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(&config.log_level))
+        .init();
+
+    if let Some(Command::Usage(UsageCommand::Report(report_args))) = &args.command {
+        return report::run(report_args, &config);
+    }
 
-    env_logger::init();
     log::info!("Starting lm-proxy...");
     log::info!(
         "Proxy configured: upstream={} listen={}",
@@ -73,11 +125,41 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         config.listen_addr
     );
 
-    let proxy = ProxyService::new(reqwest::Client::new(), config.clone());
+    let mut proxy = ProxyService::new(config.http_client()?, config.clone());
+    if let Some(path) = &config.budgets.state_file {
+        // Starting empty would overwrite the saved usage on the next flush
+        let budgets = BudgetTracker::load(path)
+            .map_err(|e| format!("failed to load budgets from {}: {}", path.display(), e))?;
+        proxy = proxy.with_budgets(budgets);
+    }
+    if let Some(path) = &config.ledger.path {
+        let ledger = Ledger::open(path, &config.ledger)
+            .map_err(|e| format!("failed to open usage ledger {}: {}", path.display(), e))?;
+        log::info!("Recording usage in {}", path.display());
+        proxy = proxy.with_ledger(ledger);
+    }
+    if let Some(path) = &config.capture.path {
+        let capture = CaptureLog::open(path, &config.capture)
+            .map_err(|e| format!("failed to open capture file {}: {}", path.display(), e))?;
+        log::info!("Capturing requests to {}", path.display());
+        proxy = proxy.with_capture(capture);
+    }
+
+    tokio::spawn(reload_on_sighup(args, proxy.clone()));
+    tokio::spawn(health::run(proxy.clone()));
+    tokio::spawn(budget::run(proxy.clone()));
+    tokio::spawn(metrics::run(proxy.clone()));
+    tokio::spawn(trace::run(proxy.clone()));
 
     let app = Router::new()
+        .route("/admin/config", get(admin::config_handler))
+        .route("/admin/upstreams", get(admin::upstreams_handler))
+        .route("/metrics", get(prometheus::handler))
+        .route_layer(axum::middleware::from_fn_with_state(proxy.clone(), admin::require_admin_key))
+        // Left open so load balancers and probes can check it without the admin key
+        .route("/healthz/upstreams", get(health::upstreams_handler))
         .route("/{*path}", any(proxy_handler))
-        .with_state(proxy);
+        .with_state(proxy.clone());
 
     let addr = config.listen_addr;
     log::info!("Listening on {}", addr);
@@ -86,5 +168,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     axum::serve(listener, app)
         .with_graceful_shutdown(shutdown_signal())
         .await?;
+
+    budget::flush(&proxy);
+    if let Some(ledger) = proxy.ledger() {
+        ledger.flush();
+    }
+    if let Some(capture) = proxy.capture() {
+        capture.flush();
+    }
+    metrics::flush(&proxy).await;
+    trace::flush(&proxy).await;
     Ok(())
 }
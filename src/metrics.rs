@@ -0,0 +1,332 @@
+//! Metric events sent to the configured sinks: `metrics_url` and StatsD.
+//! Events are queued and sent in batches by a background task so requests
+//! never wait on a metrics endpoint.
+
+use crate::batch::{self, BatchQueue};
+use crate::config::{Config, MetricsFormat, MetricsPushConfig};
+use crate::handler::{ActiveConfig, ProxyService};
+use crate::prometheus::{Labels, Metrics};
+use crate::retry;
+use crate::statsd::StatsdSink;
+use futures_util::future::{BoxFuture, join_all};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Version of the event schema, sent in every event. Bumped when fields are
+/// renamed or removed; new fields may be added without a bump.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// One metric event. `request` events carry the request's labels, status and
+/// upstream latency; `token-count` events its labels, token breakdown, latency
+/// and cost; other events only what applies to them.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MetricEvent {
+    pub version: u32,
+    pub name: String,
+    pub value: u32,
+    /// Unix time in milliseconds when the event happened
+    pub timestamp_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Name of the virtual key the request was made with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub team: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+    /// Status the client was answered with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completion_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cached_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_audio_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completion_audio_tokens: Option<u32>,
+    /// For `request` events, the time until the upstream's response headers
+    /// arrived. For `token-count` events, the time from receiving the request
+    /// until its usage was known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost_usd: Option<f64>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub estimated: bool,
+}
+
+impl MetricEvent {
+    pub fn new(name: &str, value: u32) -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            name: name.to_string(),
+            value,
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    /// An event about an upstream, e.g. a circuit opening
+    pub fn for_upstream(name: &str, upstream: &str) -> Self {
+        Self {
+            upstream: Some(upstream.to_string()),
+            ..Self::new(name, 1)
+        }
+    }
+
+    /// A `request` event for a request that got a response, or was rejected
+    /// by the proxy itself (without an upstream or latency)
+    pub fn for_request(labels: &Labels, key: Option<&str>, team: Option<&str>, latency: Option<Duration>) -> Self {
+        Self {
+            model: Some(labels.model.clone()).filter(|model| !model.is_empty()),
+            key: key.map(str::to_string),
+            team: team.map(str::to_string),
+            path: Some(labels.path.clone()),
+            upstream: Some(labels.upstream.clone()).filter(|upstream| !upstream.is_empty()),
+            status: Some(labels.status),
+            latency_ms: latency.map(|latency| latency.as_millis() as u64),
+            ..Self::new("request", 1)
+        }
+    }
+}
+
+/// Somewhere metric events are sent
+pub trait MetricsSink: Send + Sync {
+    /// Name used in logs
+    fn name(&self) -> &'static str;
+
+    /// Send a batch of events. Failed batches are dropped, so sinks retry
+    /// themselves where that makes sense.
+    fn send<'a>(&'a self, batch: &'a [MetricEvent]) -> BoxFuture<'a, Result<(), String>>;
+}
+
+/// The sinks the config enables
+pub fn sinks(config: &Config, client: &reqwest::Client) -> Vec<Arc<dyn MetricsSink>> {
+    let mut sinks: Vec<Arc<dyn MetricsSink>> = vec![];
+    if let Some(url) = &config.metrics_url {
+        sinks.push(Arc::new(HttpSink {
+            client: client.clone(),
+            url: url.clone(),
+            config: config.metrics_push.clone(),
+        }));
+    }
+    if config.statsd.addr.is_some() {
+        sinks.push(Arc::new(StatsdSink::new(config.statsd.clone())));
+    }
+    sinks
+}
+
+/// POSTs batches to `metrics_url`
+struct HttpSink {
+    client: reqwest::Client,
+    url: String,
+    config: MetricsPushConfig,
+}
+
+impl MetricsSink for HttpSink {
+    fn name(&self) -> &'static str {
+        "metrics_url"
+    }
+
+    fn send<'a>(&'a self, batch: &'a [MetricEvent]) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(send_batch(&self.client, &self.url, &self.config, batch))
+    }
+}
+
+/// Bounded queue of events waiting to be sent
+pub struct MetricsQueue {
+    events: BatchQueue<MetricEvent>,
+    /// Where dropped events are counted for `/metrics`
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsQueue {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            events: BatchQueue::new("metric events"),
+            metrics,
+        }
+    }
+
+    /// Queue an event if any sink is configured. Never blocks; drops the
+    /// event if the queue is full.
+    pub fn push(&self, active: &ActiveConfig, event: MetricEvent) {
+        if active.metrics_sinks.is_empty() {
+            return;
+        }
+        let push = &active.config.metrics_push;
+        if !self.events.push(event, push.queue_size, push.batch_size) {
+            self.record_dropped("queue_full", 1);
+        }
+    }
+
+    /// Events waiting to be sent
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    fn take_batch(&self, batch_size: usize) -> Vec<MetricEvent> {
+        self.events.take_batch(batch_size)
+    }
+
+    fn record_dropped(&self, reason: &'static str, count: u64) {
+        self.metrics.record_push_dropped(reason, count);
+        self.events.record_dropped(reason, count);
+    }
+}
+
+/// Send queued events whenever a batch fills up or the flush interval passes
+pub async fn run(proxy: ProxyService) {
+    let flush_interval = || Duration::from_millis(proxy.active_config().config.metrics_push.flush_interval_ms);
+    batch::run(&proxy.metrics_queue().events, flush_interval, || flush(&proxy)).await
+}
+
+/// Send every queued event now, e.g. before shutting down
+pub async fn flush(proxy: &ProxyService) {
+    let active = proxy.active_config();
+    let queue = proxy.metrics_queue();
+    if active.metrics_sinks.is_empty() {
+        // Every sink was removed by a reload
+        queue.take_batch(usize::MAX);
+        return;
+    }
+
+    loop {
+        let batch = queue.take_batch(active.config.metrics_push.batch_size);
+        if batch.is_empty() {
+            return;
+        }
+        let results = join_all(active.metrics_sinks.iter().map(|sink| sink.send(&batch))).await;
+        for (sink, result) in active.metrics_sinks.iter().zip(results) {
+            if let Err(e) = result {
+                log::warn!("Failed to send {} metric events to {}: {}", batch.len(), sink.name(), e);
+                queue.record_dropped("send_failed", batch.len() as u64);
+            }
+        }
+    }
+}
+
+/// POST a batch, retrying transport errors, 429s and 5xxs with backoff
+async fn send_batch(
+    client: &reqwest::Client,
+    url: &str,
+    config: &MetricsPushConfig,
+    batch: &[MetricEvent],
+) -> Result<(), String> {
+    let (body, content_type) = encode(config.format, batch);
+    let mut attempt = 0;
+    loop {
+        let result = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .body(body.clone())
+            .send()
+            .await;
+        let error = match result {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) if response.status().is_server_error() || response.status().as_u16() == 429 => {
+                format!("endpoint responded with {}", response.status())
+            }
+            Ok(response) => return Err(format!("endpoint responded with {}", response.status())),
+            Err(e) => e.to_string(),
+        };
+        if attempt >= config.max_retries {
+            return Err(error);
+        }
+        attempt += 1;
+        let delay = retry::backoff_delay(config.initial_backoff_ms, config.max_backoff_ms, true, attempt);
+        log::debug!("Retrying metrics post in {:?} after {} (retry {})", delay, error, attempt);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+fn encode(format: MetricsFormat, batch: &[MetricEvent]) -> (Vec<u8>, &'static str) {
+    match format {
+        MetricsFormat::Json => (serde_json::to_vec(batch).unwrap_or_default(), "application/json"),
+        MetricsFormat::Ndjson => {
+            let mut body = vec![];
+            for event in batch {
+                if serde_json::to_writer(&mut body, event).is_ok() {
+                    body.push(b'\n');
+                }
+            }
+            (body, "application/x-ndjson")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_is_bounded() {
+        let metrics = Arc::new(Metrics::default());
+        let queue = MetricsQueue::new(metrics.clone());
+        let mut config = Config {
+            metrics_url: Some("http://localhost:9000/metrics".to_string()),
+            ..Default::default()
+        };
+        config.metrics_push.queue_size = 2;
+        let active = ActiveConfig::new(config.clone(), reqwest::Client::new(), 1);
+
+        for _ in 0..3 {
+            queue.push(&active, MetricEvent::new("token-count", 5));
+        }
+        assert_eq!(queue.len(), 2);
+        assert!(metrics.render().contains("lm_proxy_metrics_push_dropped_total{reason=\"queue_full\"} 1"));
+
+        // Nothing is queued without a sink to send to
+        config.metrics_url = None;
+        let active = ActiveConfig::new(config, reqwest::Client::new(), 1);
+        queue.push(&active, MetricEvent::new("token-count", 5));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.take_batch(10).len(), 2);
+    }
+
+    #[test]
+    fn test_encode() {
+        let batch = [
+            MetricEvent {
+                model: Some("gpt-4o".to_string()),
+                ..MetricEvent::new("token-count", 5)
+            },
+            MetricEvent::for_upstream("circuit-opened", "vllm"),
+        ];
+
+        let (body, content_type) = encode(MetricsFormat::Json, &batch);
+        assert_eq!(content_type, "application/json");
+        let events: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(events[0]["version"], SCHEMA_VERSION);
+        assert_eq!(events[0]["model"], "gpt-4o");
+        assert_eq!(events[1]["upstream"], "vllm");
+        assert!(events[1].get("model").is_none());
+
+        let (body, content_type) = encode(MetricsFormat::Ndjson, &batch);
+        assert_eq!(content_type, "application/x-ndjson");
+        let lines: Vec<serde_json::Value> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines, events);
+    }
+}
@@ -1,23 +1,83 @@
 use serde::{Deserialize, Serialize};
 
-/// Usage statistics from OpenAI API responses
+/// Usage statistics from OpenAI API responses. The Responses API's
+/// `input_tokens`/`output_tokens` names are read into the same fields.
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Usage {
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(default, alias = "input_tokens", skip_serializing_if = "Option::is_none")]
     pub prompt_tokens: Option<u32>,
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(default, alias = "output_tokens", skip_serializing_if = "Option::is_none")]
     pub completion_tokens: Option<u32>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub total_tokens: Option<u32>,
+    #[serde(default, alias = "input_tokens_details", skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+    #[serde(default, alias = "output_tokens_details", skip_serializing_if = "Option::is_none")]
+    pub completion_tokens_details: Option<CompletionTokensDetails>,
+    /// Counted by the proxy's tokenizer because the upstream didn't report usage
+    #[serde(skip)]
+    pub estimated: bool,
+}
+
+/// Breakdown of prompt tokens
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct PromptTokensDetails {
+    /// Prompt tokens served from the upstream's prompt cache
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_tokens: Option<u32>,
+}
+
+/// Breakdown of completion tokens
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct CompletionTokensDetails {
+    /// Completion tokens spent on reasoning rather than the visible output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_tokens: Option<u32>,
 }
 
 impl Usage {
-    /// Returns a formatted string for logging
+    pub fn cached_tokens(&self) -> Option<u32> {
+        self.prompt_tokens_details.as_ref()?.cached_tokens
+    }
+
+    pub fn reasoning_tokens(&self) -> Option<u32> {
+        self.completion_tokens_details.as_ref()?.reasoning_tokens
+    }
+
+    pub fn prompt_audio_tokens(&self) -> Option<u32> {
+        self.prompt_tokens_details.as_ref()?.audio_tokens
+    }
+
+    pub fn completion_audio_tokens(&self) -> Option<u32> {
+        self.completion_tokens_details.as_ref()?.audio_tokens
+    }
+
+    /// Returns a formatted string for logging. Token details are only
+    /// included when the upstream reported them.
     pub fn log_format(&self) -> String {
-        format!(
+        let mut formatted = format!(
             "prompt_tokens={:?} completion_tokens={:?} total_tokens={:?}",
             self.prompt_tokens, self.completion_tokens, self.total_tokens
-        )
+        );
+        let details = [
+            ("cached_tokens", self.cached_tokens()),
+            ("reasoning_tokens", self.reasoning_tokens()),
+            ("prompt_audio_tokens", self.prompt_audio_tokens()),
+            ("completion_audio_tokens", self.completion_audio_tokens()),
+        ];
+        for (name, value) in details {
+            if let Some(value) = value {
+                formatted.push_str(&format!(" {}={}", name, value));
+            }
+        }
+        if self.estimated {
+            formatted.push_str(" estimated=true");
+        }
+        formatted
     }
 }
 
@@ -51,6 +111,16 @@ pub struct CompletionChunk {
     pub usage: Option<Usage>,
 }
 
+/// Responses API stream event. Events about the whole response (such as
+/// `response.completed`, which carries the usage) nest it under `response`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseStreamEvent {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<CompletionResponse>,
+}
+
 /// Individual choice in a streaming chunk
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Choice {
@@ -91,6 +161,85 @@ pub struct EmbeddingData {
     pub object: Option<String>,
 }
 
+/// OpenAI-style error response body
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: ErrorDetail,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorDetail {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub param: Option<String>,
+    pub code: Option<String>,
+}
+
+/// The fields of a request body the proxy inspects
+#[derive(Debug, Clone, Deserialize)]
+struct RequestBody {
+    #[serde(default)]
+    model: Option<String>,
+}
+
+/// Attempts to read the `model` field from a JSON request body
+pub fn try_parse_request_model(body: &[u8]) -> Option<String> {
+    serde_json::from_slice::<RequestBody>(body).ok()?.model
+}
+
+/// Replace the `model` field in a JSON body (request, response, or streaming
+/// chunk). Responses API stream events nest it under `response.model`.
+/// Returns None if the body isn't a JSON object with a model.
+pub fn rewrite_model(body: &[u8], model: &str) -> Option<Vec<u8>> {
+    let mut value = serde_json::from_slice::<serde_json::Value>(body).ok()?;
+    let object = value.as_object_mut()?;
+
+    let target = if object.contains_key("model") {
+        object
+    } else {
+        object
+            .get_mut("response")
+            .and_then(|r| r.as_object_mut())
+            .filter(|r| r.contains_key("model"))?
+    };
+    target.insert("model".to_string(), serde_json::Value::String(model.to_string()));
+
+    serde_json::to_vec(&value).ok()
+}
+
+/// Set `stream_options.include_usage` on a streaming request body so the
+/// upstream reports usage in a final chunk. Returns None if the request isn't
+/// streaming or already asks for usage.
+pub fn include_stream_usage(body: &[u8]) -> Option<Vec<u8>> {
+    let mut value = serde_json::from_slice::<serde_json::Value>(body).ok()?;
+    let object = value.as_object_mut()?;
+    if object.get("stream") != Some(&serde_json::Value::Bool(true)) {
+        return None;
+    }
+
+    let options = object
+        .entry("stream_options")
+        .or_insert_with(|| serde_json::json!({}));
+    if !options.is_object() {
+        *options = serde_json::json!({});
+    }
+    let options = options.as_object_mut()?;
+    if options.get("include_usage") == Some(&serde_json::Value::Bool(true)) {
+        return None;
+    }
+    options.insert("include_usage".to_string(), serde_json::Value::Bool(true));
+
+    serde_json::to_vec(&value).ok()
+}
+
+/// Whether a streaming chunk is the extra one sent for `include_usage`, with
+/// usage and no choices
+pub fn is_usage_only_chunk(json: &str) -> bool {
+    serde_json::from_str::<CompletionChunk>(json)
+        .is_ok_and(|chunk| chunk.usage.is_some() && chunk.choices.is_some_and(|choices| choices.is_empty()))
+}
+
 /// Attempts to parse usage from a chunk of SSE data
 /// Returns None if the chunk doesn't contain usage (most chunks don't)
 pub fn try_parse_usage_from_chunk(chunk: &str) -> Option<Usage> {
@@ -111,6 +260,11 @@ pub fn try_parse_usage_from_chunk(chunk: &str) -> Option<Usage> {
         return response.usage;
     }
 
+    // Try to parse as a Responses API event
+    if let Ok(event) = serde_json::from_str::<ResponseStreamEvent>(json) {
+        return event.response?.usage;
+    }
+
     None
 }
 
@@ -135,4 +289,109 @@ pub fn is_usage_tracked_path(path: &str) -> bool {
         || path.ends_with("completions")
         || path.ends_with("/embeddings")
         || path.contains("/responses")
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_model() {
+        let body = br#"{"model": "fast", "messages": [{"role": "user", "content": "hi"}]}"#;
+        let rewritten = rewrite_model(body, "gpt-4o-mini").unwrap();
+        assert_eq!(try_parse_request_model(&rewritten).as_deref(), Some("gpt-4o-mini"));
+
+        let event = br#"{"type": "response.completed", "response": {"id": "resp_1", "model": "gpt-4o"}}"#;
+        let rewritten: serde_json::Value =
+            serde_json::from_slice(&rewrite_model(event, "fast").unwrap()).unwrap();
+        assert_eq!(rewritten["response"]["model"], "fast");
+
+        // Fields keep their order
+        let body = br#"{"stream": true, "model": "fast", "messages": []}"#;
+        assert_eq!(rewrite_model(body, "gpt-4o").unwrap(), br#"{"stream":true,"model":"gpt-4o","messages":[]}"#);
+        let body = br#"{"stream": true, "model": "gpt-4o"}"#;
+        assert_eq!(
+            include_stream_usage(body).unwrap(),
+            br#"{"stream":true,"model":"gpt-4o","stream_options":{"include_usage":true}}"#
+        );
+
+        assert!(rewrite_model(br#"{"input": "hi"}"#, "fast").is_none());
+        assert!(rewrite_model(b"not json", "fast").is_none());
+    }
+
+    #[test]
+    fn test_include_stream_usage() {
+        let body = br#"{"model": "gpt-4o", "stream": true, "stream_options": {"include_obfuscation": false}}"#;
+        let rewritten: serde_json::Value = serde_json::from_slice(&include_stream_usage(body).unwrap()).unwrap();
+        assert_eq!(rewritten["stream_options"]["include_usage"], true);
+        assert_eq!(rewritten["stream_options"]["include_obfuscation"], false);
+
+        // Already asking for usage, or not streaming
+        assert!(include_stream_usage(&serde_json::to_vec(&rewritten).unwrap()).is_none());
+        assert!(include_stream_usage(br#"{"model": "gpt-4o", "stream": false}"#).is_none());
+        assert!(include_stream_usage(br#"{"model": "gpt-4o"}"#).is_none());
+
+        assert!(is_usage_only_chunk(
+            r#"{"id": "c1", "choices": [], "usage": {"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3}}"#
+        ));
+        assert!(!is_usage_only_chunk(r#"{"id": "c1", "choices": [{"index": 0, "delta": {}}], "usage": null}"#));
+    }
+
+    #[test]
+    fn test_parse_usage_details() {
+        let body = br#"{"id": "chatcmpl-1", "usage": {"prompt_tokens": 120, "completion_tokens": 80,
+            "total_tokens": 200, "prompt_tokens_details": {"cached_tokens": 0, "audio_tokens": 40},
+            "completion_tokens_details": {"reasoning_tokens": 0, "audio_tokens": 60,
+            "accepted_prediction_tokens": 0, "rejected_prediction_tokens": 0}}}"#;
+        let usage = try_parse_usage_from_body(body).unwrap();
+        assert_eq!(usage.cached_tokens(), Some(0));
+        assert_eq!(usage.prompt_audio_tokens(), Some(40));
+        assert_eq!(usage.completion_audio_tokens(), Some(60));
+        assert_eq!(
+            usage.log_format(),
+            "prompt_tokens=Some(120) completion_tokens=Some(80) total_tokens=Some(200) \
+             cached_tokens=0 reasoning_tokens=0 prompt_audio_tokens=40 completion_audio_tokens=60"
+        );
+
+        // Upstreams that don't report details log just the totals
+        let usage = try_parse_usage_from_chunk(r#"{"id": "c1", "usage": {"prompt_tokens": 1, "total_tokens": 1}}"#);
+        assert_eq!(
+            usage.unwrap().log_format(),
+            "prompt_tokens=Some(1) completion_tokens=None total_tokens=Some(1)"
+        );
+    }
+
+    #[test]
+    fn test_parse_responses_api_usage() {
+        let usage = r#"{"input_tokens": 36, "input_tokens_details": {"cached_tokens": 12},
+            "output_tokens": 87, "output_tokens_details": {"reasoning_tokens": 64}, "total_tokens": 123}"#;
+
+        let body = format!(r#"{{"id": "resp_1", "object": "response", "model": "o3-mini", "usage": {}}}"#, usage);
+        let parsed = try_parse_usage_from_body(body.as_bytes()).unwrap();
+        assert_eq!(parsed.prompt_tokens, Some(36));
+        assert_eq!(parsed.completion_tokens, Some(87));
+        assert_eq!(parsed.total_tokens, Some(123));
+        assert_eq!(parsed.cached_tokens(), Some(12));
+        assert_eq!(parsed.reasoning_tokens(), Some(64));
+
+        let event = format!(
+            r#"{{"type": "response.completed", "sequence_number": 9, "response": {{"id": "resp_1", "usage": {}}}}}"#,
+            usage
+        );
+        let parsed = try_parse_usage_from_chunk(&event).unwrap();
+        assert_eq!(parsed.total_tokens, Some(123));
+        assert_eq!(parsed.reasoning_tokens(), Some(64));
+
+        assert_eq!(
+            parsed.log_format(),
+            "prompt_tokens=Some(36) completion_tokens=Some(87) total_tokens=Some(123) \
+             cached_tokens=12 reasoning_tokens=64"
+        );
+
+        // Earlier events have no usage yet
+        let event = r#"{"type": "response.created", "response": {"id": "resp_1", "usage": null}}"#;
+        assert!(try_parse_usage_from_chunk(event).is_none());
+        let event = r#"{"type": "response.output_text.delta", "item_id": "msg_1", "delta": "Hi"}"#;
+        assert!(try_parse_usage_from_chunk(event).is_none());
+    }
+}
@@ -0,0 +1,109 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A string pattern from config, written either as a glob (`"gpt-*"`) or as
+/// a regex (`{ regex = "^llama-\\d+" }`). Globs support `*` and `?` and must
+/// match the whole value.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "PatternSpec", into = "PatternSpec")]
+pub struct Pattern {
+    spec: PatternSpec,
+    regex: regex::Regex,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum PatternSpec {
+    Glob(String),
+    Regex { regex: String },
+}
+
+impl Pattern {
+    pub fn glob(glob: &str) -> Result<Pattern, regex::Error> {
+        PatternSpec::Glob(glob.to_string()).try_into()
+    }
+
+    pub fn regex(regex: &str) -> Result<Pattern, regex::Error> {
+        PatternSpec::Regex {
+            regex: regex.to_string(),
+        }
+        .try_into()
+    }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        self.regex.is_match(value)
+    }
+}
+
+impl TryFrom<PatternSpec> for Pattern {
+    type Error = regex::Error;
+
+    fn try_from(spec: PatternSpec) -> Result<Self, Self::Error> {
+        let regex = match &spec {
+            PatternSpec::Glob(glob) => regex::Regex::new(&glob_to_regex(glob))?,
+            PatternSpec::Regex { regex } => regex::Regex::new(regex)?,
+        };
+        Ok(Pattern { spec, regex })
+    }
+}
+
+impl From<Pattern> for PatternSpec {
+    fn from(pattern: Pattern) -> Self {
+        pattern.spec
+    }
+}
+
+impl fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.spec {
+            PatternSpec::Glob(glob) => write!(f, "Glob({:?})", glob),
+            PatternSpec::Regex { regex } => write!(f, "Regex({:?})", regex),
+        }
+    }
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_matches_whole_value() {
+        let pattern = Pattern::glob("gpt-4?-*").unwrap();
+        assert!(pattern.is_match("gpt-4o-mini"));
+        assert!(!pattern.is_match("gpt-4-turbo"));
+        assert!(!pattern.is_match("ft:gpt-4o-mini"));
+
+        let pattern = Pattern::glob("claude.3").unwrap();
+        assert!(pattern.is_match("claude.3"));
+        assert!(!pattern.is_match("claude-3"));
+    }
+
+    #[test]
+    fn test_deserialize_glob_or_regex() {
+        #[derive(Deserialize)]
+        struct Rules {
+            patterns: Vec<Pattern>,
+        }
+
+        let rules: Rules =
+            toml::from_str(r#"patterns = ["llama-*", { regex = "^mistral-\\d+b$" }]"#).unwrap();
+        assert!(rules.patterns[0].is_match("llama-3-70b"));
+        assert!(rules.patterns[1].is_match("mistral-7b"));
+        assert!(!rules.patterns[1].is_match("mistral-large"));
+
+        assert!(toml::from_str::<Rules>(r#"patterns = [{ regex = "(" }]"#).is_err());
+    }
+}
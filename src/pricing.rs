@@ -0,0 +1,103 @@
+use crate::config::PriceConfig;
+use crate::dates;
+use crate::models::Usage;
+
+/// Cost in USD of a request's usage, using the first price that matches the
+/// model and is in effect at `now` (unix seconds). None when no price applies.
+pub fn cost_usd(prices: &[PriceConfig], model: &str, usage: &Usage, now: u64) -> Option<f64> {
+    let price = prices.iter().find(|price| {
+        price.model.is_match(model)
+            && price
+                .effective_from
+                .as_deref()
+                .and_then(dates::parse_date)
+                .is_none_or(|from| from <= now)
+    })?;
+
+    let prompt = usage.prompt_tokens.unwrap_or(0);
+    let completion = usage.completion_tokens.unwrap_or(0);
+    // Cached and reasoning tokens are included in the prompt and completion
+    // counts, so they're taken out before charging the regular rates
+    let cached = usage.cached_tokens().unwrap_or(0).min(prompt);
+    let reasoning = usage.reasoning_tokens().unwrap_or(0).min(completion);
+
+    let cost = f64::from(prompt - cached) * price.input_per_1m
+        + f64::from(cached) * price.cached_input_per_1m.unwrap_or(price.input_per_1m)
+        + f64::from(completion - reasoning) * price.output_per_1m
+        + f64::from(reasoning) * price.reasoning_per_1m.unwrap_or(price.output_per_1m);
+    Some(cost / 1_000_000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{CompletionTokensDetails, PromptTokensDetails};
+
+    // 2026-10-16T12:00:00Z
+    const NOW: u64 = 1_792_152_000;
+
+    fn prices() -> Vec<PriceConfig> {
+        toml::from_str::<crate::config::Config>(
+            r#"
+            [[prices]]
+            model = "gpt-4o-mini*"
+            input_per_1m = 0.15
+            output_per_1m = 0.6
+            cached_input_per_1m = 0.075
+
+            [[prices]]
+            model = "o3*"
+            input_per_1m = 1.0
+            output_per_1m = 4.0
+            effective_from = "2026-11-01"
+
+            [[prices]]
+            model = "o3*"
+            input_per_1m = 2.0
+            output_per_1m = 8.0
+            reasoning_per_1m = 10.0
+            "#,
+        )
+        .unwrap()
+        .prices
+    }
+
+    fn usage(prompt: u32, completion: u32, cached: Option<u32>, reasoning: Option<u32>) -> Usage {
+        Usage {
+            prompt_tokens: Some(prompt),
+            completion_tokens: Some(completion),
+            total_tokens: Some(prompt + completion),
+            prompt_tokens_details: Some(PromptTokensDetails {
+                cached_tokens: cached,
+                ..Default::default()
+            }),
+            completion_tokens_details: Some(CompletionTokensDetails {
+                reasoning_tokens: reasoning,
+                ..Default::default()
+            }),
+            estimated: false,
+        }
+    }
+
+    fn assert_cost(actual: Option<f64>, expected: f64) {
+        let actual = actual.unwrap();
+        assert!((actual - expected).abs() < 1e-12, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn test_cost_usd() {
+        let prices = prices();
+
+        // 600 uncached and 400 cached prompt tokens, 500 completion tokens
+        let cost = cost_usd(&prices, "gpt-4o-mini-2024-07-18", &usage(1000, 500, Some(400), None), NOW);
+        assert_cost(cost, (600.0 * 0.15 + 400.0 * 0.075 + 500.0 * 0.6) / 1e6);
+
+        // The cheaper o3 price isn't in effect until November
+        let cost = cost_usd(&prices, "o3-mini", &usage(1000, 500, None, Some(300)), NOW);
+        assert_cost(cost, (1000.0 * 2.0 + 200.0 * 8.0 + 300.0 * 10.0) / 1e6);
+        let cost = cost_usd(&prices, "o3-mini", &usage(1000, 500, None, Some(300)), NOW + 30 * 86_400);
+        assert_cost(cost, (1000.0 * 1.0 + 500.0 * 4.0) / 1e6);
+
+        assert!(cost_usd(&prices, "llama-3", &usage(1000, 500, None, None), NOW).is_none());
+    }
+}
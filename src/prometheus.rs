@@ -0,0 +1,319 @@
+//! Request metrics in the Prometheus text exposition format, served at
+//! `GET /metrics`
+
+use crate::handler::ProxyService;
+use crate::models::{self, Usage};
+use axum::{extract::State, http::header, response::IntoResponse};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds (seconds) of the upstream latency histogram buckets
+const LATENCY_BUCKETS: [f64; 12] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+/// Upper bounds (seconds) of the time-to-first-token histogram buckets
+const TTFT_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Labels shared by every metric
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Labels {
+    pub model: String,
+    /// The usage-tracked endpoint, e.g. `chat/completions`, or `other`, so
+    /// neither path prefixes nor ids in paths (e.g. `/files/file-abc`)
+    /// create a series each
+    pub path: String,
+    pub upstream: String,
+    pub status: u16,
+    pub streaming: bool,
+}
+
+impl Labels {
+    pub fn new(model: Option<&str>, path: &str, upstream: &str, status: u16, streaming: bool) -> Self {
+        Self {
+            model: model.unwrap_or_default().to_string(),
+            path: path_label(path).to_string(),
+            upstream: upstream.to_string(),
+            status,
+            streaming,
+        }
+    }
+
+    fn format(&self, extra: Option<(&str, &str)>) -> String {
+        let mut pairs = vec![
+            ("model", escape(&self.model)),
+            ("path", escape(&self.path)),
+            ("upstream", escape(&self.upstream)),
+            ("status", self.status.to_string()),
+            ("streaming", self.streaming.to_string()),
+        ];
+        pairs.extend(extra.map(|(name, value)| (name, escape(value))));
+        let pairs: Vec<String> = pairs.iter().map(|(name, value)| format!("{}=\"{}\"", name, value)).collect();
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+/// `/v1/chat/completions` -> `chat/completions`
+pub fn path_label(path: &str) -> &'static str {
+    if !models::is_usage_tracked_path(path) {
+        "other"
+    } else if path.contains("/chat/completions") {
+        "chat/completions"
+    } else if path.ends_with("completions") {
+        "completions"
+    } else if path.ends_with("/embeddings") {
+        "embeddings"
+    } else {
+        "responses"
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket (not cumulative), with one extra for +Inf
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = self.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    requests: BTreeMap<Labels, u64>,
+    errors: BTreeMap<Labels, u64>,
+    /// Keyed by labels and token type (prompt, completion or cached)
+    tokens: BTreeMap<(Labels, &'static str), u64>,
+    upstream_latency: BTreeMap<Labels, Histogram>,
+    time_to_first_token: BTreeMap<Labels, Histogram>,
+    /// Metric events dropped instead of sent to `metrics_url`, by reason
+    push_dropped: BTreeMap<&'static str, u64>,
+    /// Spans dropped instead of exported, by reason
+    spans_dropped: BTreeMap<&'static str, u64>,
+}
+
+/// Counters and histograms for every request the proxy handles
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+impl Metrics {
+    /// Count a finished request (or one the proxy rejected itself). Statuses
+    /// of 400 and above also count as errors. `upstream_latency` is the time
+    /// until the upstream's response headers arrived.
+    pub fn record_request(&self, labels: &Labels, upstream_latency: Option<Duration>) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.requests.entry(labels.clone()).or_default() += 1;
+        if labels.status >= 400 {
+            *inner.errors.entry(labels.clone()).or_default() += 1;
+        }
+        if let Some(latency) = upstream_latency {
+            inner
+                .upstream_latency
+                .entry(labels.clone())
+                .or_insert_with(|| Histogram::new(&LATENCY_BUCKETS))
+                .observe(latency.as_secs_f64());
+        }
+    }
+
+    pub fn record_tokens(&self, labels: &Labels, usage: &Usage) {
+        let mut inner = self.inner.lock().unwrap();
+        let counts = [
+            ("prompt", usage.prompt_tokens),
+            ("completion", usage.completion_tokens),
+            ("cached", usage.cached_tokens()),
+        ];
+        for (kind, count) in counts {
+            if let Some(count) = count {
+                *inner.tokens.entry((labels.clone(), kind)).or_default() += u64::from(count);
+            }
+        }
+    }
+
+    /// Time from receiving a streaming request until its first chunk
+    pub fn record_time_to_first_token(&self, labels: &Labels, elapsed: Duration) {
+        self.inner
+            .lock()
+            .unwrap()
+            .time_to_first_token
+            .entry(labels.clone())
+            .or_insert_with(|| Histogram::new(&TTFT_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Count metric events that were dropped, e.g. because the queue was full
+    pub fn record_push_dropped(&self, reason: &'static str, count: u64) {
+        *self.inner.lock().unwrap().push_dropped.entry(reason).or_default() += count;
+    }
+
+    /// Count spans that were dropped, e.g. because the queue was full
+    pub fn record_spans_dropped(&self, reason: &'static str, count: u64) {
+        *self.inner.lock().unwrap().spans_dropped.entry(reason).or_default() += count;
+    }
+
+    /// Render every metric in the Prometheus text format
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        write_header(&mut out, "lm_proxy_requests_total", "counter", "Requests handled, by final status");
+        for (labels, count) in &inner.requests {
+            let _ = writeln!(out, "lm_proxy_requests_total{} {}", labels.format(None), count);
+        }
+
+        write_header(
+            &mut out,
+            "lm_proxy_errors_total",
+            "counter",
+            "Requests that ended with a status of 400 or above",
+        );
+        for (labels, count) in &inner.errors {
+            let _ = writeln!(out, "lm_proxy_errors_total{} {}", labels.format(None), count);
+        }
+
+        write_header(&mut out, "lm_proxy_tokens_total", "counter", "Tokens used, by type");
+        for ((labels, kind), count) in &inner.tokens {
+            let _ = writeln!(out, "lm_proxy_tokens_total{} {}", labels.format(Some(("type", kind))), count);
+        }
+
+        write_header(
+            &mut out,
+            "lm_proxy_upstream_latency_seconds",
+            "histogram",
+            "Time until the upstream's response headers arrived",
+        );
+        for (labels, histogram) in &inner.upstream_latency {
+            write_histogram(&mut out, "lm_proxy_upstream_latency_seconds", labels, histogram);
+        }
+
+        write_header(
+            &mut out,
+            "lm_proxy_time_to_first_token_seconds",
+            "histogram",
+            "Time until the first chunk of a streaming response",
+        );
+        for (labels, histogram) in &inner.time_to_first_token {
+            write_histogram(&mut out, "lm_proxy_time_to_first_token_seconds", labels, histogram);
+        }
+
+        write_header(
+            &mut out,
+            "lm_proxy_metrics_push_dropped_total",
+            "counter",
+            "Metric events dropped instead of sent to metrics_url, by reason",
+        );
+        for (reason, count) in &inner.push_dropped {
+            let _ = writeln!(out, "lm_proxy_metrics_push_dropped_total{{reason=\"{}\"}} {}", reason, count);
+        }
+
+        write_header(
+            &mut out,
+            "lm_proxy_trace_spans_dropped_total",
+            "counter",
+            "Spans dropped instead of exported, by reason",
+        );
+        for (reason, count) in &inner.spans_dropped {
+            let _ = writeln!(out, "lm_proxy_trace_spans_dropped_total{{reason=\"{}\"}} {}", reason, count);
+        }
+        out
+    }
+}
+
+/// Serves `GET /metrics`
+pub async fn handler(State(proxy): State<ProxyService>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        proxy.metrics().render(),
+    )
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_histogram(out: &mut String, name: &str, labels: &Labels, histogram: &Histogram) {
+    let mut cumulative = 0;
+    for (i, count) in histogram.counts.iter().enumerate() {
+        cumulative += count;
+        let bound = histogram.bounds.get(i).map_or("+Inf".to_string(), |bound| bound.to_string());
+        let _ = writeln!(out, "{}_bucket{} {}", name, labels.format(Some(("le", &bound))), cumulative);
+    }
+    let _ = writeln!(out, "{}_sum{} {}", name, labels.format(None), histogram.sum);
+    let _ = writeln!(out, "{}_count{} {}", name, labels.format(None), cumulative);
+}
+
+/// Escape a label value as the text format requires
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        let ok = Labels::new(Some("gpt-4o"), "/v1/chat/completions", "default", 200, true);
+        metrics.record_request(&ok, Some(Duration::from_millis(300)));
+        metrics.record_request(&ok, Some(Duration::from_secs(2)));
+        metrics.record_time_to_first_token(&ok, Duration::from_millis(400));
+        metrics.record_tokens(
+            &ok,
+            &Usage {
+                prompt_tokens: Some(10),
+                completion_tokens: Some(5),
+                ..Default::default()
+            },
+        );
+        let rejected = Labels::new(Some("a \"quoted\" model"), "/v1/files/file-abc", "", 401, false);
+        metrics.record_request(&rejected, None);
+
+        let text = metrics.render();
+        let labels = r#"model="gpt-4o",path="chat/completions",upstream="default",status="200",streaming="true""#;
+        let rejected = r#"model="a \"quoted\" model",path="other",upstream="",status="401",streaming="false""#;
+        for line in [
+            format!("lm_proxy_requests_total{{{}}} 2", labels),
+            format!("lm_proxy_requests_total{{{}}} 1", rejected),
+            format!("lm_proxy_errors_total{{{}}} 1", rejected),
+            format!("lm_proxy_tokens_total{{{},type=\"prompt\"}} 10", labels),
+            format!("lm_proxy_tokens_total{{{},type=\"completion\"}} 5", labels),
+            format!("lm_proxy_upstream_latency_seconds_bucket{{{},le=\"0.25\"}} 0", labels),
+            format!("lm_proxy_upstream_latency_seconds_bucket{{{},le=\"0.5\"}} 1", labels),
+            format!("lm_proxy_upstream_latency_seconds_bucket{{{},le=\"+Inf\"}} 2", labels),
+            format!("lm_proxy_upstream_latency_seconds_sum{{{}}} 2.3", labels),
+            format!("lm_proxy_upstream_latency_seconds_count{{{}}} 2", labels),
+            format!("lm_proxy_time_to_first_token_seconds_bucket{{{},le=\"0.5\"}} 1", labels),
+            "# TYPE lm_proxy_upstream_latency_seconds histogram".to_string(),
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {}\n{}", line, text);
+        }
+        assert!(!text.contains(&format!("lm_proxy_errors_total{{{}}}", labels)));
+    }
+
+    #[test]
+    fn test_path_label() {
+        assert_eq!(path_label("/v1/chat/completions"), "chat/completions");
+        assert_eq!(path_label("/openai/deployments/gpt-4o/chat/completions"), "chat/completions");
+        assert_eq!(path_label("/v1/completions"), "completions");
+        assert_eq!(path_label("/local/embeddings"), "embeddings");
+        assert_eq!(path_label("/v1/responses/resp_123"), "responses");
+        assert_eq!(path_label("/v1/files/file-abc"), "other");
+    }
+}
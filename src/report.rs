@@ -0,0 +1,412 @@
+use crate::config::Config;
+use crate::{dates, ledger};
+use rusqlite::{Connection, types::Value};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Arguments for `lm-proxy usage report`
+#[derive(Debug, Clone, clap::Args)]
+pub struct ReportArgs {
+    /// Only include requests from this long ago (e.g. 24h, 7d, 4w) or since a
+    /// date (YYYY-MM-DD, UTC)
+    #[arg(long, default_value = "7d")]
+    pub since: String,
+
+    /// Comma-separated fields to group by
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "key,model")]
+    pub group_by: Vec<GroupBy>,
+
+    #[arg(long, value_enum, default_value_t = Format::Table)]
+    pub format: Format,
+
+    /// Only include requests with this tag (from the `x-lm-proxy-tags` header)
+    #[arg(long)]
+    pub tag: Option<String>,
+
+    /// Only include requests served by this upstream
+    #[arg(long)]
+    pub upstream: Option<String>,
+
+    /// Only include requests to this path or paths under it
+    #[arg(long)]
+    pub path: Option<String>,
+
+    /// Ledger database to read [default: `ledger.path` from the config]
+    #[arg(long)]
+    pub db: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum GroupBy {
+    Key,
+    Team,
+    Model,
+    Upstream,
+    Path,
+    Status,
+    /// Calendar day (UTC)
+    Day,
+}
+
+impl GroupBy {
+    fn name(self) -> &'static str {
+        match self {
+            GroupBy::Key => "key",
+            GroupBy::Team => "team",
+            GroupBy::Model => "model",
+            GroupBy::Upstream => "upstream",
+            GroupBy::Path => "path",
+            GroupBy::Status => "status",
+            GroupBy::Day => "day",
+        }
+    }
+
+    fn column(self) -> &'static str {
+        match self {
+            GroupBy::Status => "CAST(status AS TEXT)",
+            GroupBy::Day => "date(timestamp_ms / 1000, 'unixepoch')",
+            other => other.name(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    Table,
+    Csv,
+    Json,
+}
+
+/// Usage totals for one group
+#[derive(Debug, PartialEq)]
+pub struct ReportRow {
+    /// Values of the group-by fields, in order. None where the ledger has no
+    /// value, e.g. requests made without a virtual key.
+    pub group: Vec<Option<String>>,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    pub cached_tokens: u64,
+    /// Total cost of the requests whose model has a price
+    pub cost_usd: f64,
+}
+
+const TOTAL_COLUMNS: [&str; 5] = [
+    "requests",
+    "prompt_tokens",
+    "completion_tokens",
+    "total_tokens",
+    "cached_tokens",
+];
+
+impl ReportRow {
+    fn totals(&self) -> [u64; 5] {
+        [
+            self.requests,
+            self.prompt_tokens,
+            self.completion_tokens,
+            self.total_tokens,
+            self.cached_tokens,
+        ]
+    }
+}
+
+/// Print a usage report from the ledger
+pub fn run(args: &ReportArgs, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let path = args
+        .db
+        .clone()
+        .or_else(|| config.ledger.path.clone())
+        .ok_or("no usage ledger configured; set `ledger.path` in the config or pass --db")?;
+    if !path.exists() {
+        return Err(format!("usage ledger {} does not exist", path.display()).into());
+    }
+
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+    let since_ms = parse_since(&args.since, now_ms)?;
+    let connection = open(&path)?;
+    let rows = query(&connection, args, since_ms)?;
+    print!("{}", render(&rows, &args.group_by, args.format));
+    Ok(())
+}
+
+/// Open the ledger read-only, so a report never changes it, even while the
+/// proxy is writing to it
+fn open(path: &Path) -> Result<Connection, Box<dyn std::error::Error>> {
+    let connection = ledger::connect_read_only(path)?;
+    if !ledger::is_up_to_date(&connection)? {
+        return Err(format!(
+            "usage ledger {} was written by an older version; start the proxy with it to upgrade it",
+            path.display()
+        )
+        .into());
+    }
+    Ok(connection)
+}
+
+/// Total up usage since `since_ms`, grouped and filtered as requested, with
+/// the largest token users first
+pub fn query(connection: &Connection, args: &ReportArgs, since_ms: u64) -> rusqlite::Result<Vec<ReportRow>> {
+    let mut conditions = vec!["timestamp_ms >= ?".to_string()];
+    let mut params = vec![Value::Integer(since_ms as i64)];
+    if let Some(tag) = &args.tag {
+        conditions.push("instr(tags, ?) > 0".to_string());
+        params.push(Value::Text(format!(",{},", tag)));
+    }
+    if let Some(upstream) = &args.upstream {
+        conditions.push("upstream = ?".to_string());
+        params.push(Value::Text(upstream.clone()));
+    }
+    if let Some(path) = &args.path {
+        let path = path.trim_end_matches('/');
+        conditions.push("(path = ? OR substr(path, 1, ?) = ?)".to_string());
+        params.push(Value::Text(path.to_string()));
+        params.push(Value::Integer(path.len() as i64 + 1));
+        params.push(Value::Text(format!("{}/", path)));
+    }
+
+    let groups: Vec<String> = args.group_by.iter().map(|g| g.column().to_string()).collect();
+    let mut select = groups.clone();
+    select.extend([
+        "COUNT(*)".to_string(),
+        "COALESCE(SUM(prompt_tokens), 0)".to_string(),
+        "COALESCE(SUM(completion_tokens), 0)".to_string(),
+        "COALESCE(SUM(total_tokens), 0)".to_string(),
+        "COALESCE(SUM(cached_tokens), 0)".to_string(),
+        "COALESCE(SUM(cost_usd), 0)".to_string(),
+    ]);
+    let mut sql = format!("SELECT {} FROM usage WHERE {}", select.join(", "), conditions.join(" AND "));
+    if !groups.is_empty() {
+        sql.push_str(&format!(" GROUP BY {}", groups.join(", ")));
+    }
+    sql.push_str(" ORDER BY SUM(total_tokens) DESC");
+
+    let width = groups.len();
+    let mut statement = connection.prepare(&sql)?;
+    let rows = statement.query_map(rusqlite::params_from_iter(params), |row| {
+        let total = |i: usize| row.get::<_, i64>(width + i).map(|v| v as u64);
+        Ok(ReportRow {
+            group: (0..width).map(|i| row.get(i)).collect::<Result<_, _>>()?,
+            requests: total(0)?,
+            prompt_tokens: total(1)?,
+            completion_tokens: total(2)?,
+            total_tokens: total(3)?,
+            cached_tokens: total(4)?,
+            cost_usd: row.get(width + 5)?,
+        })
+    })?;
+    let rows: Vec<ReportRow> = rows.collect::<Result<_, _>>()?;
+    // Without grouping an empty ledger still yields one all-zero row
+    Ok(rows.into_iter().filter(|row| row.requests > 0).collect())
+}
+
+pub fn render(rows: &[ReportRow], group_by: &[GroupBy], format: Format) -> String {
+    let header: Vec<&str> = group_by
+        .iter()
+        .map(|g| g.name())
+        .chain(TOTAL_COLUMNS)
+        .chain(["cost_usd"])
+        .collect();
+    let cells = |row: &ReportRow, empty: &str| -> Vec<String> {
+        row.group
+            .iter()
+            .map(|value| value.clone().unwrap_or_else(|| empty.to_string()))
+            .chain(row.totals().iter().map(u64::to_string))
+            .chain([format!("{:.6}", row.cost_usd)])
+            .collect()
+    };
+
+    match format {
+        Format::Table => {
+            let body: Vec<Vec<String>> = rows.iter().map(|row| cells(row, "-")).collect();
+            let widths: Vec<usize> = (0..header.len())
+                .map(|i| body.iter().map(|r| r[i].len()).chain([header[i].len()]).max().unwrap_or(0))
+                .collect();
+            let line = |values: Vec<String>| {
+                let padded: Vec<String> = values
+                    .iter()
+                    .enumerate()
+                    .map(|(i, value)| {
+                        // Left-align group values, right-align totals
+                        if i < group_by.len() {
+                            format!("{:<width$}", value, width = widths[i])
+                        } else {
+                            format!("{:>width$}", value, width = widths[i])
+                        }
+                    })
+                    .collect();
+                format!("{}\n", padded.join("  ").trim_end())
+            };
+            let mut out = line(header.iter().map(|h| h.to_string()).collect());
+            for row in body {
+                out.push_str(&line(row));
+            }
+            out
+        }
+        Format::Csv => {
+            let mut out = format!("{}\n", header.join(","));
+            for row in rows {
+                let values: Vec<String> = cells(row, "").iter().map(|v| csv_escape(v)).collect();
+                out.push_str(&format!("{}\n", values.join(",")));
+            }
+            out
+        }
+        Format::Json => {
+            let objects: Vec<serde_json::Value> = rows
+                .iter()
+                .map(|row| {
+                    let mut object = serde_json::Map::new();
+                    for (group, value) in group_by.iter().zip(&row.group) {
+                        object.insert(group.name().to_string(), value.clone().into());
+                    }
+                    for (name, total) in TOTAL_COLUMNS.iter().zip(row.totals()) {
+                        object.insert(name.to_string(), total.into());
+                    }
+                    object.insert("cost_usd".to_string(), row.cost_usd.into());
+                    object.into()
+                })
+                .collect();
+            format!("{}\n", serde_json::to_string_pretty(&objects).unwrap_or_default())
+        }
+    }
+}
+
+fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Parse `--since` as a duration before `now_ms` (e.g. "90m", "7d", "2w") or
+/// a UTC date, returning unix milliseconds
+fn parse_since(value: &str, now_ms: u64) -> Result<u64, String> {
+    let invalid = || format!("invalid --since `{}`, expected e.g. 24h, 7d or 2026-01-31", value);
+
+    if value.contains('-') {
+        return dates::parse_date(value).and_then(|secs| secs.checked_mul(1000)).ok_or_else(invalid);
+    }
+
+    let split = value.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let amount: u64 = value[..split].parse().map_err(|_| invalid())?;
+    let unit_secs = match &value[split..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        "w" => 604_800,
+        _ => return Err(invalid()),
+    };
+    let ago_ms = amount.checked_mul(unit_secs * 1000).ok_or_else(invalid)?;
+    Ok(now_ms.saturating_sub(ago_ms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LedgerConfig;
+    use crate::ledger::{Ledger, UsageRecord};
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        report: ReportArgs,
+    }
+
+    fn args(argv: &[&str]) -> ReportArgs {
+        Cli::parse_from(std::iter::once("report").chain(argv.iter().copied())).report
+    }
+
+    #[test]
+    fn test_parse_since() {
+        let now = 1_792_152_000_000;
+        assert_eq!(parse_since("7d", now), Ok(now - 7 * 86_400_000));
+        assert_eq!(parse_since("90m", now), Ok(now - 90 * 60_000));
+        assert_eq!(parse_since("2026-10-16", now), Ok(1_792_108_800_000));
+        assert_eq!(parse_since("1970-01-01", now), Ok(0));
+        assert!(parse_since("7 days", now).is_err());
+        assert!(parse_since("2026-13-01", now).is_err());
+        assert!(parse_since("99999999999999999d", now).is_err());
+        assert!(parse_since("99999999999999999999d", now).is_err());
+        assert!(parse_since("99999999999999999-01-01", now).is_err());
+    }
+
+    #[test]
+    fn test_report() {
+        let path = std::env::temp_dir().join(format!("lm-proxy-report-{}.db", std::process::id()));
+        let ledger = Ledger::open(&path, &LedgerConfig::default()).unwrap();
+        let record = |key: &str, model: &str, tags: &[&str], upstream: &str, total: u32| UsageRecord {
+            timestamp_ms: 1_792_152_000_000,
+            request_id: "req".to_string(),
+            key: Some(key.to_string()),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            model: Some(model.to_string()),
+            upstream: upstream.to_string(),
+            path: "/v1/chat/completions".to_string(),
+            prompt_tokens: Some(total - 1),
+            completion_tokens: Some(1),
+            total_tokens: Some(total),
+            cost_usd: Some(f64::from(total) / 1000.0),
+            status: 200,
+            ..Default::default()
+        };
+        ledger.record(record("ci", "gpt-4o", &["eval"], "openai", 10));
+        ledger.record(record("ci", "gpt-4o", &[], "openai", 20));
+        ledger.record(record("ci", "llama-3", &["eval", "batch"], "vllm", 5));
+        ledger.record(record("web", "gpt-4o", &[], "openai", 100));
+        ledger.flush();
+        let connection = open(&path).unwrap();
+
+        let rows = query(&connection, &args(&[]), 0).unwrap();
+        let groups: Vec<(Vec<Option<String>>, u64, u64)> =
+            rows.iter().map(|r| (r.group.clone(), r.requests, r.total_tokens)).collect();
+        let group = |key: &str, model: &str| vec![Some(key.to_string()), Some(model.to_string())];
+        assert_eq!(
+            groups,
+            vec![(group("web", "gpt-4o"), 1, 100), (group("ci", "gpt-4o"), 2, 30), (group("ci", "llama-3"), 1, 5)]
+        );
+
+        let rows = query(&connection, &args(&["--group-by", "upstream", "--tag", "eval"]), 0).unwrap();
+        let table = render(&rows, &[GroupBy::Upstream], Format::Table);
+        assert_eq!(
+            table,
+            "upstream  requests  prompt_tokens  completion_tokens  total_tokens  cached_tokens  cost_usd\n\
+             openai           1              9                  1            10              0  0.010000\n\
+             vllm             1              4                  1             5              0  0.005000\n"
+        );
+
+        let rows = query(&connection, &args(&["--group-by", "key", "--path", "/v1", "--upstream", "vllm"]), 0).unwrap();
+        assert_eq!(
+            render(&rows, &[GroupBy::Key], Format::Csv),
+            "key,requests,prompt_tokens,completion_tokens,total_tokens,cached_tokens,cost_usd\nci,1,4,1,5,0,0.005000\n"
+        );
+        let json: serde_json::Value = serde_json::from_str(&render(&rows, &[GroupBy::Key], Format::Json)).unwrap();
+        assert_eq!(json[0]["key"], "ci");
+        assert_eq!(json[0]["total_tokens"], 5);
+        assert_eq!(json[0]["cost_usd"], 0.005);
+
+        // Later than every record
+        assert!(query(&connection, &args(&[]), 1_800_000_000_000).unwrap().is_empty());
+
+        drop(ledger);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn test_report_does_not_migrate() {
+        let path = std::env::temp_dir().join(format!("lm-proxy-report-old-{}.db", std::process::id()));
+        Connection::open(&path).unwrap().pragma_update(None, "user_version", 1).unwrap();
+
+        let err = open(&path).unwrap_err();
+        let connection = Connection::open(&path).unwrap();
+        let version: i64 = connection.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.to_string().contains("older version"), "{}", err);
+        assert_eq!(version, 1);
+    }
+}
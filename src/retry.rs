@@ -0,0 +1,264 @@
+use crate::config::RetryConfig;
+use axum::http::{self, HeaderMap};
+use std::time::{Duration, SystemTime};
+
+/// Whether a request may be retried. Request bodies are fully buffered before
+/// forwarding so they can always be replayed; the remaining question is
+/// whether sending it twice is safe.
+pub fn is_retryable_request(config: &RetryConfig, method: &http::Method, is_llm_request: bool) -> bool {
+    config.max_attempts > 1 && (is_llm_request || is_idempotent(method) || config.non_llm_requests)
+}
+
+fn is_idempotent(method: &http::Method) -> bool {
+    matches!(
+        *method,
+        http::Method::GET
+            | http::Method::HEAD
+            | http::Method::PUT
+            | http::Method::DELETE
+            | http::Method::OPTIONS
+            | http::Method::TRACE
+    )
+}
+
+/// Whether a transport error is worth retrying
+pub fn is_retryable_error(config: &RetryConfig, error: &reqwest::Error) -> bool {
+    config.transport_errors && (error.is_connect() || error.is_timeout())
+}
+
+/// Delay before retrying a response with a retryable status. Uses the delay
+/// the upstream asked for when present, otherwise exponential backoff.
+/// Returns None when the upstream asks to wait longer than allowed.
+pub fn response_delay(
+    config: &RetryConfig,
+    attempt: u32,
+    status: http::StatusCode,
+    headers: &HeaderMap,
+) -> Option<Duration> {
+    match server_delay(status, headers) {
+        Some(delay) if delay > Duration::from_secs(config.max_retry_after_secs) => None,
+        Some(delay) => Some(delay),
+        None => Some(backoff_delay(config.initial_backoff_ms, config.max_backoff_ms, config.jitter, attempt)),
+    }
+}
+
+/// Exponential backoff for the given attempt (1 for the first retry). With
+/// jitter, a random delay between half and all of it.
+pub fn backoff_delay(initial_ms: u64, max_ms: u64, jitter: bool, attempt: u32) -> Duration {
+    let exponent = attempt.saturating_sub(1).min(31);
+    let delay_ms = initial_ms.saturating_mul(1 << exponent).min(max_ms);
+
+    let delay_ms = if jitter && delay_ms > 0 {
+        rand::random_range(delay_ms / 2..=delay_ms)
+    } else {
+        delay_ms
+    };
+    Duration::from_millis(delay_ms)
+}
+
+/// Delay requested by the upstream through `Retry-After` (seconds or an HTTP
+/// date), `retry-after-ms`, or OpenAI's `x-ratelimit-reset-*` headers. The
+/// reset headers come with every response, so they're only used for a 429 or
+/// when a limit is exhausted.
+pub fn server_delay(status: http::StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim);
+
+    if let Some(ms) = header("retry-after-ms").and_then(|v| v.parse::<f64>().ok())
+        && let Some(delay) = duration_from_secs(ms / 1000.0)
+    {
+        return Some(delay);
+    }
+
+    if let Some(value) = header("retry-after") {
+        if let Ok(secs) = value.parse::<u64>() {
+            return Some(Duration::from_secs(secs));
+        }
+        if let Ok(date) = httpdate::parse_http_date(value) {
+            return Some(date.duration_since(SystemTime::now()).unwrap_or_default());
+        }
+    }
+
+    // Prefer the reset for whichever limit is exhausted, otherwise wait for both
+    // if rate limited
+    let limits = ["requests", "tokens"].map(|limit| {
+        let reset = header(&format!("x-ratelimit-reset-{}", limit)).and_then(parse_reset_duration);
+        let exhausted = header(&format!("x-ratelimit-remaining-{}", limit)) == Some("0");
+        (reset, exhausted)
+    });
+    let exhausted = limits
+        .iter()
+        .filter(|(_, exhausted)| *exhausted)
+        .filter_map(|(reset, _)| *reset)
+        .max();
+    if exhausted.is_some() || status != http::StatusCode::TOO_MANY_REQUESTS {
+        return exhausted;
+    }
+    limits.iter().filter_map(|(reset, _)| *reset).max()
+}
+
+/// A delay from a header's (possibly fractional) seconds. Values too large
+/// for a `Duration` are the longest delay, which is over any cap; NaN is
+/// ignored.
+fn duration_from_secs(secs: f64) -> Option<Duration> {
+    if secs.is_nan() {
+        return None;
+    }
+    Some(Duration::try_from_secs_f64(secs.max(0.0)).unwrap_or(Duration::MAX))
+}
+
+/// Parse durations like "1s", "6m0s", "20ms", or "1h2m3.5s"
+fn parse_reset_duration(value: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let number_len = rest.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+        let number: f64 = rest[..number_len].parse().ok()?;
+        rest = &rest[number_len..];
+
+        let unit_len = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let multiplier = match &rest[..unit_len] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 0.001,
+            _ => return None,
+        };
+        rest = &rest[unit_len..];
+        total += number * multiplier;
+    }
+    if value.is_empty() {
+        return None;
+    }
+    duration_from_secs(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{HeaderValue, StatusCode};
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_parse_reset_duration() {
+        assert_eq!(parse_reset_duration("1s"), Some(Duration::from_secs(1)));
+        assert_eq!(parse_reset_duration("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(parse_reset_duration("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(parse_reset_duration("1h2m3.5s"), Some(Duration::from_millis(3_723_500)));
+        assert_eq!(parse_reset_duration(""), None);
+        assert_eq!(parse_reset_duration("soon"), None);
+        assert_eq!(parse_reset_duration("5d"), None);
+        // Overflows to infinity
+        let digits = format!("{}s", "9".repeat(400));
+        assert_eq!(parse_reset_duration(&digits), Some(Duration::MAX));
+    }
+
+    #[test]
+    fn test_server_delay() {
+        let status = StatusCode::TOO_MANY_REQUESTS;
+        assert_eq!(server_delay(status, &headers(&[("retry-after", "3")])), Some(Duration::from_secs(3)));
+        assert_eq!(
+            server_delay(status, &headers(&[("retry-after-ms", "250")])),
+            Some(Duration::from_millis(250))
+        );
+        // Dates in the past mean retry now
+        assert_eq!(
+            server_delay(status, &headers(&[("retry-after", "Wed, 21 Oct 2015 07:28:00 GMT")])),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            server_delay(status, &headers(&[
+                ("x-ratelimit-remaining-requests", "0"),
+                ("x-ratelimit-reset-requests", "2s"),
+                ("x-ratelimit-remaining-tokens", "1000"),
+                ("x-ratelimit-reset-tokens", "1m"),
+            ])),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            server_delay(status, &headers(&[
+                ("x-ratelimit-reset-requests", "2s"),
+                ("x-ratelimit-reset-tokens", "5s"),
+            ])),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(server_delay(status, &headers(&[])), None);
+
+        // Reset headers are sent with every response, but only mean "wait" for
+        // a 429 or an exhausted limit
+        let resets = [("x-ratelimit-reset-requests", "2s"), ("x-ratelimit-reset-tokens", "5m")];
+        assert_eq!(server_delay(StatusCode::SERVICE_UNAVAILABLE, &headers(&resets)), None);
+        assert_eq!(
+            server_delay(
+                StatusCode::SERVICE_UNAVAILABLE,
+                &headers(&[("x-ratelimit-remaining-requests", "0"), resets[0], resets[1]])
+            ),
+            Some(Duration::from_secs(2))
+        );
+    }
+
+    #[test]
+    fn test_response_delay_respects_cap() {
+        let config = RetryConfig {
+            max_retry_after_secs: 10,
+            jitter: false,
+            ..Default::default()
+        };
+        let status = StatusCode::TOO_MANY_REQUESTS;
+        assert_eq!(
+            response_delay(&config, 1, status, &headers(&[("retry-after", "5")])),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(response_delay(&config, 1, status, &headers(&[("retry-after", "30")])), None);
+
+        // Values too large for a Duration are over the cap rather than a panic
+        assert_eq!(response_delay(&config, 1, status, &headers(&[("retry-after-ms", "inf")])), None);
+        assert_eq!(response_delay(&config, 1, status, &headers(&[("retry-after-ms", "1e30")])), None);
+        let mut reset = headers(&[]);
+        reset.insert("x-ratelimit-reset-tokens", HeaderValue::from_str(&format!("{}s", "9".repeat(400))).unwrap());
+        assert_eq!(response_delay(&config, 1, status, &reset), None);
+        // NaN is ignored in favor of the backoff
+        assert_eq!(
+            response_delay(&config, 1, status, &headers(&[("retry-after-ms", "NaN")])),
+            Some(Duration::from_millis(config.initial_backoff_ms))
+        );
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        assert_eq!(backoff_delay(100, 1000, false, 1), Duration::from_millis(100));
+        assert_eq!(backoff_delay(100, 1000, false, 3), Duration::from_millis(400));
+        assert_eq!(backoff_delay(100, 1000, false, 10), Duration::from_millis(1000));
+
+        for _ in 0..100 {
+            let delay = backoff_delay(100, 1000, true, 2);
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn test_is_retryable_request() {
+        let config = RetryConfig {
+            max_attempts: 3,
+            ..Default::default()
+        };
+        assert!(is_retryable_request(&config, &http::Method::POST, true));
+        assert!(is_retryable_request(&config, &http::Method::GET, false));
+        assert!(!is_retryable_request(&config, &http::Method::POST, false));
+
+        let opted_in = RetryConfig {
+            non_llm_requests: true,
+            ..config.clone()
+        };
+        assert!(is_retryable_request(&opted_in, &http::Method::POST, false));
+
+        let disabled = RetryConfig::default();
+        assert!(!is_retryable_request(&disabled, &http::Method::GET, true));
+    }
+}
@@ -0,0 +1,299 @@
+use crate::config::{Config, FallbackConfig, RouteConfig, UpstreamConfig};
+use axum::http;
+use std::fmt;
+
+/// The upstream chosen for a request and the path to forward to it
+#[derive(Debug, Clone)]
+pub struct ResolvedRoute {
+    pub upstream: UpstreamConfig,
+    /// Path (and query) after any prefix stripping or rewriting
+    pub path: String,
+    /// Upstreams to try, in order, if `upstream` fails
+    pub fallbacks: Vec<UpstreamTarget>,
+}
+
+/// An upstream to send a request to, optionally with a different model
+#[derive(Debug, Clone)]
+pub struct UpstreamTarget {
+    pub upstream: UpstreamConfig,
+    /// Replaces the request's model when set
+    pub model: Option<String>,
+}
+
+impl ResolvedRoute {
+    /// Returns the full upstream URL for this route
+    pub fn upstream_url(&self) -> String {
+        self.upstream.url_for_path(&self.path)
+    }
+
+    /// The primary upstream followed by its fallbacks, in the order to try them
+    pub fn targets(&self) -> Vec<UpstreamTarget> {
+        let primary = UpstreamTarget {
+            upstream: self.upstream.clone(),
+            model: None,
+        };
+        std::iter::once(primary).chain(self.fallbacks.iter().cloned()).collect()
+    }
+}
+
+/// Errors for requests that can't be routed to any upstream
+#[derive(Debug)]
+pub enum RoutingError {
+    /// Model routes are configured but none match the requested model
+    ModelNotFound(String),
+}
+
+impl fmt::Display for RoutingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoutingError::ModelNotFound(model) => write!(
+                f,
+                "The model `{}` does not exist or you do not have access to it.",
+                model
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RoutingError {}
+
+/// Pick the upstream for a request. Model routes are checked first when the
+/// request names a model, then path routes, falling back to the default
+/// upstream when nothing matches. A model-routed request still gets the path
+/// rewrite of the path route its path matches, if any.
+pub fn resolve(
+    config: &Config,
+    method: &http::Method,
+    host: Option<&str>,
+    path: &str,
+    query: &str,
+    model: Option<&str>,
+) -> Result<ResolvedRoute, RoutingError> {
+    // Routes are validated to reference known upstreams at load time
+    let path_route = config
+        .routes
+        .iter()
+        .filter(|route| route_matches(route, method, host, path))
+        .find_map(|route| Some((route, config.upstream(&route.upstream)?)));
+    let rewritten = match path_route {
+        Some((route, _)) => rewrite_path(route, path),
+        None => path.to_string(),
+    };
+
+    if let Some(model) = model
+        && !config.model_routes.is_empty()
+    {
+        let model_route = config
+            .model_routes
+            .iter()
+            .find(|route| route.model.is_match(model))
+            .and_then(|route| Some((route, config.upstream(&route.upstream)?)));
+        match model_route {
+            Some((route, upstream)) => {
+                return Ok(ResolvedRoute {
+                    upstream,
+                    path: format!("{}{}", rewritten, query),
+                    fallbacks: resolve_fallbacks(config, &route.fallbacks),
+                });
+            }
+            // A path route still serves models that no model route lists
+            None if path_route.is_some() => {}
+            None => return Err(RoutingError::ModelNotFound(model.to_string())),
+        }
+    }
+
+    if let Some((route, upstream)) = path_route {
+        return Ok(ResolvedRoute {
+            upstream,
+            path: format!("{}{}", rewritten, query),
+            fallbacks: resolve_fallbacks(config, &route.fallbacks),
+        });
+    }
+
+    Ok(ResolvedRoute {
+        upstream: config.default_upstream(),
+        path: format!("{}{}", path, query),
+        fallbacks: resolve_fallbacks(config, &config.fallbacks),
+    })
+}
+
+fn resolve_fallbacks(config: &Config, fallbacks: &[FallbackConfig]) -> Vec<UpstreamTarget> {
+    fallbacks
+        .iter()
+        .filter_map(|fallback| {
+            Some(UpstreamTarget {
+                upstream: config.upstream(&fallback.upstream)?,
+                model: fallback.model.clone(),
+            })
+        })
+        .collect()
+}
+
+fn route_matches(route: &RouteConfig, method: &http::Method, host: Option<&str>, path: &str) -> bool {
+    if let Some(prefix) = &route.path_prefix
+        && !path_has_prefix(path, prefix)
+    {
+        return false;
+    }
+
+    if let Some(expected) = &route.host {
+        let Some(host) = host else { return false };
+        if !host_matches(expected, host) {
+            return false;
+        }
+    }
+
+    route.methods.is_empty() || route.methods.iter().any(|m| m.eq_ignore_ascii_case(method.as_str()))
+}
+
+/// Prefix match that only matches whole path segments, so `/v1` matches
+/// `/v1` and `/v1/models` but not `/v10`
+fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Compare hosts case-insensitively, ignoring the request's port unless the
+/// rule specifies one
+fn host_matches(expected: &str, host: &str) -> bool {
+    if expected.contains(':') {
+        return expected.eq_ignore_ascii_case(host);
+    }
+    let hostname = host.rsplit_once(':').map_or(host, |(name, _)| name);
+    expected.eq_ignore_ascii_case(hostname)
+}
+
+fn rewrite_path(route: &RouteConfig, path: &str) -> String {
+    let Some(prefix) = &route.path_prefix else {
+        return path.to_string();
+    };
+    let replacement = match (&route.rewrite_prefix, route.strip_prefix) {
+        (Some(rewrite), _) => rewrite.trim_end_matches('/'),
+        (None, true) => "",
+        (None, false) => return path.to_string(),
+    };
+
+    let rest = &path[prefix.trim_end_matches('/').len()..];
+    let rewritten = format!("{}{}", replacement, rest);
+    if rewritten.starts_with('/') {
+        rewritten
+    } else {
+        format!("/{}", rewritten)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            upstream_url = "https://api.openai.com/v1"
+
+            [[upstreams]]
+            name = "vllm"
+            url = "http://localhost:8000/v1"
+
+            [[upstreams]]
+            name = "embeddings"
+            url = "http://localhost:9000"
+
+            [[routes]]
+            path_prefix = "/local"
+            upstream = "vllm"
+            strip_prefix = true
+
+            [[routes]]
+            path_prefix = "/embed"
+            upstream = "embeddings"
+            rewrite_prefix = "/v2"
+            methods = ["POST"]
+
+            [[routes]]
+            host = "vllm.internal"
+            upstream = "vllm"
+
+            [[model_routes]]
+            model = "llama-*"
+            upstream = "vllm"
+            fallbacks = [{ upstream = "default", model = "gpt-4o-mini" }]
+
+            [[model_routes]]
+            model = { regex = "^text-embedding-" }
+            upstream = "embeddings"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_strip_prefix() {
+        let route = resolve(&config(), &http::Method::POST, None, "/local/chat/completions", "", None).unwrap();
+        assert_eq!(route.upstream.name, "vllm");
+        assert_eq!(route.upstream_url(), "http://localhost:8000/v1/chat/completions");
+    }
+
+    #[test]
+    fn test_rewrite_prefix_and_method() {
+        let route = resolve(&config(), &http::Method::POST, None, "/embed/embeddings", "?a=1", None).unwrap();
+        assert_eq!(route.upstream.name, "embeddings");
+        assert_eq!(route.upstream_url(), "http://localhost:9000/v2/embeddings?a=1");
+
+        // Method doesn't match so it falls through to the default upstream
+        let route = resolve(&config(), &http::Method::GET, None, "/embed/embeddings", "", None).unwrap();
+        assert_eq!(route.upstream.name, "default");
+        assert_eq!(route.upstream_url(), "https://api.openai.com/v1/embed/embeddings");
+    }
+
+    #[test]
+    fn test_host_match_ignores_port() {
+        let route = resolve(&config(), &http::Method::GET, Some("vllm.internal:3000"), "/models", "", None).unwrap();
+        assert_eq!(route.upstream.name, "vllm");
+        assert_eq!(route.path, "/models");
+    }
+
+    #[test]
+    fn test_prefix_matches_whole_segments() {
+        let route = resolve(&config(), &http::Method::GET, None, "/localhost/models", "", None).unwrap();
+        assert_eq!(route.upstream.name, "default");
+
+        let route = resolve(&config(), &http::Method::GET, None, "/local", "", None).unwrap();
+        assert_eq!(route.upstream.name, "vllm");
+        assert_eq!(route.path, "/");
+    }
+
+    #[test]
+    fn test_model_routes_take_precedence() {
+        let route = resolve(&config(), &http::Method::POST, None, "/chat/completions", "", Some("llama-3-8b")).unwrap();
+        assert_eq!(route.upstream.name, "vllm");
+        assert_eq!(route.upstream_url(), "http://localhost:8000/v1/chat/completions");
+        assert_eq!(route.fallbacks.len(), 1);
+        assert_eq!(route.fallbacks[0].upstream.name, "default");
+        assert_eq!(route.fallbacks[0].model.as_deref(), Some("gpt-4o-mini"));
+
+        let route = resolve(&config(), &http::Method::POST, None, "/local/embeddings", "", Some("text-embedding-3-small")).unwrap();
+        assert_eq!(route.upstream.name, "embeddings");
+        // The /local route's prefix is still stripped
+        assert_eq!(route.path, "/embeddings");
+        assert_eq!(route.upstream_url(), "http://localhost:9000/embeddings");
+    }
+
+    #[test]
+    fn test_unmatched_model_is_not_found() {
+        let err = resolve(&config(), &http::Method::POST, None, "/v1/chat/completions", "", Some("gpt-4o")).unwrap_err();
+        assert!(matches!(err, RoutingError::ModelNotFound(model) if model == "gpt-4o"));
+
+        // Requests without a model still use path routing
+        let route = resolve(&config(), &http::Method::POST, None, "/v1/chat/completions", "", None).unwrap();
+        assert_eq!(route.upstream.name, "default");
+
+        // As do models without a model route, on a path a path route serves
+        let route = resolve(&config(), &http::Method::POST, None, "/local/chat/completions", "", Some("gpt-4o")).unwrap();
+        assert_eq!(route.upstream.name, "vllm");
+        assert_eq!(route.path, "/chat/completions");
+    }
+}
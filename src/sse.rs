@@ -0,0 +1,164 @@
+//! Incremental decoding of server-sent event streams, following the WHATWG
+//! event stream format. Network chunks can end anywhere, including in the
+//! middle of a line or between the `\r` and `\n` of a line break.
+
+/// A complete event from the stream
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Event {
+    /// The `event:` field, e.g. `response.completed` in Responses API streams
+    pub event: Option<String>,
+    pub id: Option<String>,
+    /// The event's `data:` lines, joined with newlines
+    pub data: String,
+}
+
+/// Buffers partial lines and events between chunks
+#[derive(Debug, Default)]
+pub struct Decoder {
+    /// Start of a line whose line break hasn't arrived yet
+    line: Vec<u8>,
+    /// The last chunk ended with `\r`, so a `\n` starting the next one is part
+    /// of the same line break
+    after_cr: bool,
+    event: Event,
+    has_data: bool,
+}
+
+impl Decoder {
+    /// Decode a chunk, returning the events it completes
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<Event> {
+        let mut events = vec![];
+        let mut rest = chunk;
+        if self.after_cr && rest.first() == Some(&b'\n') {
+            rest = &rest[1..];
+        }
+        self.after_cr = false;
+
+        while let Some(end) = rest.iter().position(|b| *b == b'\n' || *b == b'\r') {
+            self.line.extend_from_slice(&rest[..end]);
+            let line = std::mem::take(&mut self.line);
+            events.extend(self.process_line(&line));
+
+            let break_len = match rest.get(end + 1) {
+                _ if rest[end] == b'\n' => 1,
+                Some(b'\n') => 2,
+                Some(_) => 1,
+                None => {
+                    self.after_cr = true;
+                    1
+                }
+            };
+            rest = &rest[end + break_len..];
+        }
+        self.line.extend_from_slice(rest);
+        events
+    }
+
+    fn process_line(&mut self, line: &[u8]) -> Option<Event> {
+        // A blank line ends the event. Events without data aren't dispatched.
+        if line.is_empty() {
+            let event = std::mem::take(&mut self.event);
+            return std::mem::take(&mut self.has_data).then_some(event);
+        }
+
+        let line = String::from_utf8_lossy(line);
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (&*line, ""),
+        };
+        match field {
+            "event" => self.event.event = Some(value.to_string()),
+            "id" => self.event.id = Some(value.to_string()),
+            "data" => {
+                if self.has_data {
+                    self.event.data.push('\n');
+                }
+                self.event.data.push_str(value);
+                self.has_data = true;
+            }
+            // Comments (lines starting with `:`), `retry` and unknown fields
+            _ => {}
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    const STREAM: &str = concat!(
+        ": keep-alive\r\n",
+        "\r\n",
+        "event: response.created\r\n",
+        "id: 1\r\n",
+        "data: {\"type\": \"response.created\"}\r\n",
+        "\r\n",
+        "data: first line\n",
+        "data:second line\n",
+        "data\n",
+        "\n",
+        "event: ping\r",
+        "\r",
+        "data: {\"usage\": {\"total_tokens\": 5}, \"text\": \"héllo\"}\r",
+        "\r",
+        "data: [DONE]\n",
+        "\n",
+        "data: never finished",
+    );
+
+    fn expected() -> Vec<Event> {
+        vec![
+            Event {
+                event: Some("response.created".to_string()),
+                id: Some("1".to_string()),
+                data: r#"{"type": "response.created"}"#.to_string(),
+            },
+            Event {
+                data: "first line\nsecond line\n".to_string(),
+                ..Default::default()
+            },
+            Event {
+                data: r#"{"usage": {"total_tokens": 5}, "text": "héllo"}"#.to_string(),
+                ..Default::default()
+            },
+            Event {
+                data: "[DONE]".to_string(),
+                ..Default::default()
+            },
+        ]
+    }
+
+    fn decode(chunks: &[&[u8]]) -> Vec<Event> {
+        let mut decoder = Decoder::default();
+        chunks.iter().flat_map(|chunk| decoder.feed(chunk)).collect()
+    }
+
+    #[test]
+    fn test_decode_whole_stream() {
+        assert_eq!(decode(&[STREAM.as_bytes()]), expected());
+    }
+
+    #[test]
+    fn test_decode_byte_at_a_time() {
+        let chunks: Vec<&[u8]> = STREAM.as_bytes().chunks(1).collect();
+        assert_eq!(decode(&chunks), expected());
+    }
+
+    #[test]
+    fn test_decode_random_chunk_boundaries() {
+        let mut rng = StdRng::seed_from_u64(0x55e);
+        let bytes = STREAM.as_bytes();
+        for _ in 0..500 {
+            let mut chunks: Vec<&[u8]> = vec![];
+            let mut rest = bytes;
+            while !rest.is_empty() {
+                let (chunk, tail) = rest.split_at(rng.random_range(1..=rest.len().min(24)));
+                chunks.push(chunk);
+                rest = tail;
+            }
+            assert_eq!(decode(&chunks), expected(), "chunks: {:?}", chunks);
+        }
+    }
+}
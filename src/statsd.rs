@@ -0,0 +1,200 @@
+//! Metric events sent to a StatsD or DogStatsD agent over UDP. Requests and
+//! tokens are counters, latencies are timings, and the request's labels are
+//! DogStatsD tags.
+
+use crate::config::{StatsdConfig, StatsdFlavor};
+use crate::metrics::{MetricEvent, MetricsSink};
+use futures_util::future::BoxFuture;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+use tokio::sync::OnceCell;
+
+pub struct StatsdSink {
+    config: StatsdConfig,
+    /// Bound on first use, for the agent's address family
+    socket: OnceCell<UdpSocket>,
+}
+
+impl StatsdSink {
+    pub fn new(config: StatsdConfig) -> Self {
+        Self {
+            config,
+            socket: OnceCell::new(),
+        }
+    }
+
+    async fn send_batch(&self, batch: &[MetricEvent]) -> Result<(), String> {
+        let Some(addr) = &self.config.addr else {
+            return Ok(());
+        };
+        // Resolved for every batch so a DNS change is picked up
+        let target = tokio::net::lookup_host(addr)
+            .await
+            .map_err(|e| format!("failed to resolve {}: {}", addr, e))?
+            .next()
+            .ok_or_else(|| format!("{} did not resolve to an address", addr))?;
+        let socket = self
+            .socket
+            .get_or_try_init(|| {
+                let local = match target {
+                    SocketAddr::V4(_) => "0.0.0.0:0",
+                    SocketAddr::V6(_) => "[::]:0",
+                };
+                UdpSocket::bind(local)
+            })
+            .await
+            .map_err(|e| format!("failed to bind a UDP socket: {}", e))?;
+
+        let lines: Vec<String> = batch.iter().flat_map(|event| lines(&self.config, event)).collect();
+        for packet in packets(&lines, self.config.max_packet_bytes) {
+            socket
+                .send_to(packet.as_bytes(), target)
+                .await
+                .map_err(|e| format!("failed to send to {}: {}", target, e))?;
+        }
+        Ok(())
+    }
+}
+
+impl MetricsSink for StatsdSink {
+    fn name(&self) -> &'static str {
+        "statsd"
+    }
+
+    fn send<'a>(&'a self, batch: &'a [MetricEvent]) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(self.send_batch(batch))
+    }
+}
+
+/// The StatsD lines for an event
+fn lines(config: &StatsdConfig, event: &MetricEvent) -> Vec<String> {
+    let line = |name: &str, value: String, kind: &str, extra: Option<(&str, &str)>| {
+        let mut line = if config.prefix.is_empty() {
+            format!("{}:{}|{}", name, value, kind)
+        } else {
+            format!("{}.{}:{}|{}", config.prefix, name, value, kind)
+        };
+        if config.flavor == StatsdFlavor::Dogstatsd {
+            let tags = tags(event, extra);
+            if !tags.is_empty() {
+                line.push_str("|#");
+                line.push_str(&tags.join(","));
+            }
+        }
+        line
+    };
+
+    let mut lines = vec![];
+    match event.name.as_str() {
+        "request" => {
+            lines.push(line("requests", event.value.to_string(), "c", None));
+            if let Some(latency) = event.latency_ms {
+                lines.push(line("upstream_latency", latency.to_string(), "ms", None));
+            }
+        }
+        "token-count" => {
+            let counts = [
+                ("prompt", event.prompt_tokens),
+                ("completion", event.completion_tokens),
+                ("cached", event.cached_tokens),
+                ("reasoning", event.reasoning_tokens),
+            ];
+            for (kind, count) in counts {
+                if let Some(count) = count {
+                    lines.push(line("tokens", count.to_string(), "c", Some(("type", kind))));
+                }
+            }
+            if let Some(latency) = event.latency_ms {
+                lines.push(line("request_duration", latency.to_string(), "ms", None));
+            }
+        }
+        // e.g. `circuit-opened` is counted as `circuit_opened`
+        name => lines.push(line(&name.replace('-', "_"), event.value.to_string(), "c", None)),
+    }
+    lines
+}
+
+/// DogStatsD tags for the event's labels
+fn tags(event: &MetricEvent, extra: Option<(&str, &str)>) -> Vec<String> {
+    let status = event.status.map(|status| status.to_string());
+    let labels = [
+        ("model", event.model.as_deref()),
+        ("key", event.key.as_deref()),
+        ("team", event.team.as_deref()),
+        ("path", event.path.as_deref()),
+        ("upstream", event.upstream.as_deref()),
+        ("status", status.as_deref()),
+        ("estimated", event.estimated.then_some("true")),
+    ];
+    labels
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+        .chain(extra)
+        .map(|(name, value)| format!("{}:{}", name, value.replace([',', '|', '#', '\n'], "_")))
+        .collect()
+}
+
+/// Pack lines into newline-separated datagrams of at most `max_bytes`. A
+/// longer line is sent on its own.
+fn packets(lines: &[String], max_bytes: usize) -> Vec<String> {
+    let mut packets: Vec<String> = vec![];
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > max_bytes {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lines() {
+        let mut config = StatsdConfig::default();
+        let event = MetricEvent {
+            model: Some("gpt-4o".to_string()),
+            key: Some("ci".to_string()),
+            path: Some("/v1/chat/completions".to_string()),
+            prompt_tokens: Some(10),
+            completion_tokens: Some(5),
+            latency_ms: Some(1200),
+            ..MetricEvent::new("token-count", 15)
+        };
+        let tags = "model:gpt-4o,key:ci,path:/v1/chat/completions";
+        assert_eq!(
+            lines(&config, &event),
+            [
+                format!("lm_proxy.tokens:10|c|#{},type:prompt", tags),
+                format!("lm_proxy.tokens:5|c|#{},type:completion", tags),
+                format!("lm_proxy.request_duration:1200|ms|#{}", tags),
+            ]
+        );
+
+        config.flavor = StatsdFlavor::Statsd;
+        config.prefix = String::new();
+        let event = MetricEvent::for_upstream("circuit-opened", "vllm,east");
+        assert_eq!(lines(&config, &event), ["circuit_opened:1|c"]);
+        config.flavor = StatsdFlavor::Dogstatsd;
+        assert_eq!(lines(&config, &event), ["circuit_opened:1|c|#upstream:vllm_east"]);
+    }
+
+    #[test]
+    fn test_packets() {
+        let lines: Vec<String> = ["a:1|c", "b:1|c", "c:1|c", "a-much-longer-line:1|c"]
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        assert_eq!(packets(&lines, 11), ["a:1|c\nb:1|c", "c:1|c", "a-much-longer-line:1|c"]);
+        assert!(packets(&[], 11).is_empty());
+    }
+}
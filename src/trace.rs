@@ -0,0 +1,565 @@
+//! OpenTelemetry traces exported over OTLP/HTTP (JSON encoding). Each request
+//! gets a server span, with child spans for reading the request body, each
+//! upstream attempt, and the phases of the upstream response. Model calls
+//! follow the GenAI semantic conventions.
+
+use crate::batch::{self, BatchQueue};
+use crate::handler::{ActiveConfig, ProxyService};
+use crate::prometheus::Metrics;
+use serde_json::{Value, json};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// W3C trace context header, read from requests and sent upstream
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// Parent of a request's spans, from an incoming `traceparent` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// Parse a version 00 `traceparent` (e.g.
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`). Later
+    /// versions are read the same way, as the spec asks.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+        if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        if trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+            return None;
+        }
+        let is_hex = |s: &str| s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+        if ![version, trace_id, span_id, flags].into_iter().all(is_hex) {
+            return None;
+        }
+        let context = Self {
+            trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+            span_id: u64::from_str_radix(span_id, 16).ok()?,
+            sampled: u8::from_str_radix(flags, 16).ok()? & 1 == 1,
+        };
+        (context.trace_id != 0 && context.span_id != 0).then_some(context)
+    }
+
+    pub fn to_header(&self) -> String {
+        format!("00-{:032x}-{:016x}-{:02x}", self.trace_id, self.span_id, u8::from(self.sampled))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_string())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<u32> for AttributeValue {
+    fn from(value: u32) -> Self {
+        Self::Int(value.into())
+    }
+}
+
+impl From<u16> for AttributeValue {
+    fn from(value: u16) -> Self {
+        Self::Int(value.into())
+    }
+}
+
+impl From<bool> for AttributeValue {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub parent_span_id: Option<u64>,
+    pub name: String,
+    pub kind: SpanKind,
+    pub start: SystemTime,
+    pub end: Option<SystemTime>,
+    pub attributes: Vec<(&'static str, AttributeValue)>,
+    /// Set when the operation failed
+    pub error: Option<String>,
+}
+
+impl Span {
+    fn new(trace_id: u128, parent_span_id: Option<u64>, name: &str, kind: SpanKind) -> Self {
+        Self {
+            trace_id,
+            span_id: new_span_id(),
+            parent_span_id,
+            name: name.to_string(),
+            kind,
+            start: SystemTime::now(),
+            end: None,
+            attributes: vec![],
+            error: None,
+        }
+    }
+
+    /// Start a span under this one
+    pub fn child(&self, name: &str, kind: SpanKind) -> Span {
+        Span::new(self.trace_id, Some(self.span_id), name, kind)
+    }
+
+    pub fn set(&mut self, key: &'static str, value: impl Into<AttributeValue>) {
+        let value = value.into();
+        match self.attributes.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, existing)) => *existing = value,
+            None => self.attributes.push((key, value)),
+        }
+    }
+
+    fn to_otlp(&self) -> Value {
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    AttributeValue::String(s) => json!({"stringValue": s}),
+                    // 64-bit integers are strings in OTLP's JSON encoding
+                    AttributeValue::Int(i) => json!({"intValue": i.to_string()}),
+                    AttributeValue::Bool(b) => json!({"boolValue": b}),
+                };
+                json!({"key": key, "value": value})
+            })
+            .collect();
+        let mut span = json!({
+            "traceId": format!("{:032x}", self.trace_id),
+            "spanId": format!("{:016x}", self.span_id),
+            "name": self.name,
+            "kind": self.kind as u8,
+            "startTimeUnixNano": unix_nanos(self.start).to_string(),
+            "endTimeUnixNano": unix_nanos(self.end.unwrap_or(self.start)).to_string(),
+            "attributes": attributes,
+        });
+        if let Some(parent) = self.parent_span_id {
+            span["parentSpanId"] = json!(format!("{:016x}", parent));
+        }
+        if let Some(message) = &self.error {
+            span["status"] = json!({"code": 2, "message": message});
+        }
+        span
+    }
+}
+
+/// Spans of one request. Cloned into whatever needs to add spans; the server
+/// span ends when the last clone is dropped, i.e. once the response is done.
+#[derive(Clone)]
+pub struct RequestTrace(Option<Arc<TraceState>>);
+
+struct TraceState {
+    active: Arc<ActiveConfig>,
+    tracer: Arc<Tracer>,
+    sampled: bool,
+    server: Mutex<Span>,
+}
+
+impl Drop for TraceState {
+    fn drop(&mut self) {
+        let mut server = self.server.lock().unwrap().clone();
+        server.end = Some(SystemTime::now());
+        if self.sampled {
+            self.tracer.push(&self.active, server);
+        }
+    }
+}
+
+impl RequestTrace {
+    /// A trace that records nothing, for when tracing is off
+    pub fn disabled() -> Self {
+        Self(None)
+    }
+
+    /// Start the server span for a request, continuing the caller's trace if
+    /// it sent a valid `traceparent`
+    pub fn start(
+        active: Arc<ActiveConfig>,
+        tracer: Arc<Tracer>,
+        method: &str,
+        path: &str,
+        parent: Option<&str>,
+    ) -> Self {
+        if active.config.tracing.otlp_endpoint.is_none() {
+            return Self::disabled();
+        }
+        let parent = parent.and_then(TraceContext::parse);
+        let sampled = match parent {
+            Some(parent) => parent.sampled,
+            None => rand::random::<f64>() < active.config.tracing.sample_ratio,
+        };
+        let trace_id = parent.map_or_else(new_trace_id, |parent| parent.trace_id);
+        let name = if crate::models::is_usage_tracked_path(path) {
+            format!("{} {}", method, path)
+        } else {
+            method.to_string()
+        };
+        let mut server = Span::new(trace_id, parent.map(|parent| parent.span_id), &name, SpanKind::Server);
+        server.set("http.request.method", method);
+        server.set("url.path", path);
+        Self(Some(Arc::new(TraceState {
+            active,
+            tracer,
+            sampled,
+            server: Mutex::new(server),
+        })))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Start a span under the server span. `None` when tracing is off.
+    pub fn child(&self, name: &str, kind: SpanKind) -> Option<Span> {
+        let state = self.0.as_ref()?;
+        Some(state.server.lock().unwrap().child(name, kind))
+    }
+
+    /// Set an attribute on the server span
+    pub fn set(&self, key: &'static str, value: impl Into<AttributeValue>) {
+        if let Some(state) = &self.0 {
+            state.server.lock().unwrap().set(key, value);
+        }
+    }
+
+    /// Record the status the client is answered with. 5xx statuses mark the
+    /// server span as failed.
+    pub fn set_status(&self, status: u16) {
+        if let Some(state) = &self.0 {
+            let mut server = state.server.lock().unwrap();
+            server.set("http.response.status_code", status);
+            if status >= 500 {
+                server.set("error.type", status.to_string());
+                server.error = Some(format!("responded with {}", status));
+            }
+        }
+    }
+
+    /// The `traceparent` to send upstream, naming `span` as the parent
+    pub fn traceparent(&self, span: &Span) -> Option<String> {
+        let state = self.0.as_ref()?;
+        let context = TraceContext {
+            trace_id: span.trace_id,
+            span_id: span.span_id,
+            sampled: state.sampled,
+        };
+        Some(context.to_header())
+    }
+
+    /// End a span (at `end`, or now) and queue it for export
+    pub fn finish(&self, mut span: Span, end: Option<SystemTime>) {
+        if let Some(state) = &self.0
+            && state.sampled
+        {
+            span.end = Some(end.unwrap_or_else(SystemTime::now));
+            state.tracer.push(&state.active, span);
+        }
+    }
+}
+
+/// Bounded queue of finished spans waiting to be exported
+pub struct Tracer {
+    spans: BatchQueue<Span>,
+    /// Where dropped spans are counted for `/metrics`
+    metrics: Arc<Metrics>,
+}
+
+impl Tracer {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            spans: BatchQueue::new("spans"),
+            metrics,
+        }
+    }
+
+    fn push(&self, active: &ActiveConfig, span: Span) {
+        let config = &active.config.tracing;
+        if !self.spans.push(span, config.queue_size, config.batch_size) {
+            self.record_dropped("queue_full", 1);
+        }
+    }
+
+    /// Spans waiting to be exported
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    fn take_batch(&self, batch_size: usize) -> Vec<Span> {
+        self.spans.take_batch(batch_size)
+    }
+
+    fn record_dropped(&self, reason: &'static str, count: u64) {
+        self.metrics.record_spans_dropped(reason, count);
+        self.spans.record_dropped(reason, count);
+    }
+}
+
+/// Export spans whenever a batch fills up or the flush interval passes
+pub async fn run(proxy: ProxyService) {
+    let flush_interval = || Duration::from_millis(proxy.active_config().config.tracing.flush_interval_ms);
+    batch::run(&proxy.tracer().spans, flush_interval, || flush(&proxy)).await
+}
+
+/// Export every queued span now, e.g. before shutting down
+pub async fn flush(proxy: &ProxyService) {
+    let active = proxy.active_config();
+    let tracer = proxy.tracer();
+    let config = &active.config.tracing;
+    let Some(endpoint) = &config.otlp_endpoint else {
+        // Tracing was turned off by a reload
+        tracer.take_batch(usize::MAX);
+        return;
+    };
+
+    loop {
+        let batch = tracer.take_batch(config.batch_size);
+        if batch.is_empty() {
+            return;
+        }
+        let mut request = active.client.post(endpoint).json(&export_request(&config.service_name, &batch));
+        for (name, value) in &config.headers {
+            request = request.header(name, value);
+        }
+        let result = request.send().await.and_then(|response| response.error_for_status());
+        if let Err(e) = result {
+            log::warn!("Failed to export {} spans: {}", batch.len(), e);
+            tracer.record_dropped("export_failed", batch.len() as u64);
+        }
+    }
+}
+
+/// An OTLP `ExportTraceServiceRequest` in its JSON encoding
+fn export_request(service_name: &str, spans: &[Span]) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{"key": "service.name", "value": {"stringValue": service_name}}],
+            },
+            "scopeSpans": [{
+                "scope": {"name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION")},
+                "spans": spans.iter().map(Span::to_otlp).collect::<Vec<_>>(),
+            }],
+        }],
+    })
+}
+
+/// The GenAI operation (`gen_ai.operation.name`) an endpoint performs
+pub fn gen_ai_operation(path: &str) -> Option<&'static str> {
+    if path.ends_with("/chat/completions") || path.ends_with("/responses") {
+        Some("chat")
+    } else if path.ends_with("/completions") {
+        Some("text_completion")
+    } else if path.ends_with("/embeddings") {
+        Some("embeddings")
+    } else {
+        None
+    }
+}
+
+fn new_trace_id() -> u128 {
+    rand::random::<u128>().max(1)
+}
+
+fn new_span_id() -> u64 {
+    rand::random::<u64>().max(1)
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default()
+}
+
+tokio::task_local! {
+    /// When the upstream connection for the current request was opened, if
+    /// it needed a new one
+    static CONNECT_TIMING: ConnectTiming;
+}
+
+/// Start and end of opening an upstream connection. Pooled connections are
+/// reused without one.
+#[derive(Clone, Default)]
+pub struct ConnectTiming(Arc<Mutex<ConnectTimes>>);
+
+#[derive(Clone, Copy, Default)]
+struct ConnectTimes {
+    start: Option<SystemTime>,
+    end: Option<SystemTime>,
+}
+
+impl ConnectTiming {
+    /// Run `future` (an upstream request), noting any connection it opens
+    pub async fn scope<F: Future>(&self, future: F) -> F::Output {
+        CONNECT_TIMING.scope(self.clone(), future).await
+    }
+
+    /// When the connection was started and finished opening
+    pub fn get(&self) -> Option<(SystemTime, SystemTime)> {
+        let times = *self.0.lock().unwrap();
+        Some((times.start?, times.end?))
+    }
+}
+
+/// Wraps the HTTP client's connector to time new connections
+#[derive(Clone)]
+pub struct ConnectTimingLayer;
+
+impl<S> tower::Layer<S> for ConnectTimingLayer {
+    type Service = ConnectTimingService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConnectTimingService { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct ConnectTimingService<S> {
+    inner: S,
+}
+
+impl<S, R> tower::Service<R> for ConnectTimingService<S>
+where
+    S: tower::Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let timing = CONNECT_TIMING.try_with(ConnectTiming::clone).ok();
+        if let Some(timing) = &timing {
+            *timing.0.lock().unwrap() = ConnectTimes {
+                start: Some(SystemTime::now()),
+                end: None,
+            };
+        }
+        let connecting = self.inner.call(request);
+        Box::pin(async move {
+            let result = connecting.await;
+            if let Some(timing) = timing {
+                timing.0.lock().unwrap().end = Some(SystemTime::now());
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_traceparent() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = TraceContext::parse(header).unwrap();
+        assert_eq!(context.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert_eq!(context.span_id, 0x00f067aa0ba902b7);
+        assert!(context.sampled);
+        assert_eq!(context.to_header(), header);
+
+        let unsampled = TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00").unwrap();
+        assert!(!unsampled.sampled);
+        // Later versions may add fields
+        assert!(TraceContext::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra").is_some());
+
+        for invalid in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+        ] {
+            assert!(TraceContext::parse(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_export_request() {
+        let mut server = Span::new(1, Some(2), "POST /v1/chat/completions", SpanKind::Server);
+        server.set("http.response.status_code", 502u16);
+        server.error = Some("responded with 502".to_string());
+        let mut client = server.child("chat gpt-4o", SpanKind::Client);
+        client.set("gen_ai.request.model", "gpt-4o");
+        client.set("gen_ai.usage.input_tokens", 10u32);
+        client.set("gen_ai.request.model", "gpt-4o-mini");
+
+        let request = export_request("lm-proxy", &[server.clone(), client.clone()]);
+        let spans = &request["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(
+            request["resourceSpans"][0]["resource"]["attributes"][0],
+            json!({"key": "service.name", "value": {"stringValue": "lm-proxy"}})
+        );
+        assert_eq!(spans[0]["traceId"], "00000000000000000000000000000001");
+        assert_eq!(spans[0]["parentSpanId"], "0000000000000002");
+        assert_eq!(spans[0]["kind"], 2);
+        assert_eq!(spans[0]["status"], json!({"code": 2, "message": "responded with 502"}));
+        assert_eq!(spans[1]["parentSpanId"], format!("{:016x}", server.span_id));
+        assert_eq!(spans[1]["kind"], 3);
+        assert_eq!(
+            spans[1]["attributes"],
+            json!([
+                {"key": "gen_ai.request.model", "value": {"stringValue": "gpt-4o-mini"}},
+                {"key": "gen_ai.usage.input_tokens", "value": {"intValue": "10"}},
+            ])
+        );
+        assert!(spans[1].get("status").is_none());
+    }
+
+    #[test]
+    fn test_dropped_spans_are_counted() {
+        let metrics = Arc::new(Metrics::default());
+        let tracer = Tracer::new(metrics.clone());
+        let mut config = Config::default();
+        config.tracing.queue_size = 1;
+        let active = ActiveConfig::new(config, reqwest::Client::new(), 1);
+
+        for _ in 0..2 {
+            tracer.push(&active, Span::new(new_trace_id(), None, "test", SpanKind::Internal));
+        }
+        assert_eq!(tracer.len(), 1);
+        assert!(metrics.render().contains("lm_proxy_trace_spans_dropped_total{reason=\"queue_full\"} 1"));
+    }
+}
@@ -0,0 +1,560 @@
+use crate::auth;
+use crate::circuit_breaker::{BreakerStatus, CircuitBreaker, CircuitOpen, Transition};
+use crate::config::{BalanceStrategy, CircuitBreakerConfig, HealthCheckConfig, MemberConfig, UpstreamConfig};
+use axum::http;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Runtime state for every upstream, kept across config reloads. State for an
+/// upstream is rebuilt when its members change.
+#[derive(Default)]
+pub struct UpstreamRegistry {
+    states: Mutex<HashMap<String, Arc<UpstreamState>>>,
+}
+
+impl UpstreamRegistry {
+    /// Returns the state for an upstream, creating or rebuilding it if the
+    /// upstream's members changed since it was last used
+    pub fn state(&self, upstream: &UpstreamConfig) -> Arc<UpstreamState> {
+        let members = upstream.effective_members();
+        let mut states = self.states.lock().unwrap();
+        match states.get(&upstream.name) {
+            Some(state) if state.members == members => state.clone(),
+            _ => {
+                let state = Arc::new(UpstreamState::new(members));
+                states.insert(upstream.name.clone(), state.clone());
+                state
+            }
+        }
+    }
+
+    /// Choose a healthy member of the upstream's pool for a request. Fails
+    /// fast when every member is unhealthy or the circuit breaker is open.
+    pub fn select(&self, upstream: &UpstreamConfig) -> Result<SelectedMember, Unavailable> {
+        let state = self.state(upstream);
+        let usable = state.usable_members(upstream);
+        if !usable.contains(&true) {
+            return Err(Unavailable::Unhealthy);
+        }
+        let probe = match &upstream.circuit_breaker {
+            Some(config) => state.breaker.acquire(config)?,
+            None => false,
+        };
+        let index = state.choose(upstream.balance, &usable);
+        state.stats[index].outstanding.fetch_add(1, Ordering::Relaxed);
+
+        let member = &state.members[index];
+        Ok(SelectedMember {
+            upstream: upstream.name.clone(),
+            name: member.name.clone(),
+            url: member.url.clone().unwrap_or_else(|| upstream.url.clone()),
+            api_key: member.api_key.clone(),
+            auth_header: upstream.auth_header.clone(),
+            is_pooled: !upstream.members.is_empty(),
+            breaker: upstream.circuit_breaker.clone(),
+            probe,
+            recorded: AtomicBool::new(false),
+            state: state.clone(),
+            index,
+        })
+    }
+
+    /// Health checks for the upstream's members that haven't run within the
+    /// check interval. Each returned check is marked as started.
+    pub fn due_health_checks(&self, upstream: &UpstreamConfig) -> Vec<HealthCheck> {
+        let Some(config) = &upstream.health_check else {
+            return vec![];
+        };
+        let state = self.state(upstream);
+        let interval = Duration::from_secs(config.interval_secs);
+
+        let mut checks = vec![];
+        for (index, member) in state.members.iter().enumerate() {
+            let mut health = state.stats[index].health.lock().unwrap();
+            if health.last_started.is_some_and(|started| started.elapsed() < interval) {
+                continue;
+            }
+            health.last_started = Some(Instant::now());
+
+            let url = member.url.as_deref().unwrap_or(&upstream.url);
+            let mut headers = http::HeaderMap::new();
+            set_credentials(&mut headers, member.api_key.as_deref(), &upstream.auth_header);
+            checks.push(HealthCheck {
+                upstream: upstream.name.clone(),
+                member: member.name.clone(),
+                url: format!("{}{}", url.trim_end_matches('/'), config.path),
+                headers,
+                config: config.clone(),
+                state: state.clone(),
+                index,
+            });
+        }
+        checks
+    }
+
+    /// Current health, breaker and member state for an upstream
+    pub fn status(&self, upstream: &UpstreamConfig) -> UpstreamStatus {
+        let state = self.state(upstream);
+        let usable = state.usable_members(upstream);
+        let breaker = upstream.circuit_breaker.as_ref().map(|_| state.breaker.status());
+        UpstreamStatus {
+            name: upstream.name.clone(),
+            healthy: usable.contains(&true) && breaker.as_ref().is_none_or(|b| b.state != "open"),
+            circuit_breaker: breaker,
+            members: state
+                .members
+                .iter()
+                .zip(&state.stats)
+                .zip(usable)
+                .map(|((member, stats), healthy)| {
+                    let health = stats.health.lock().unwrap();
+                    MemberStatus {
+                        name: member.name.clone(),
+                        healthy,
+                        outstanding: stats.outstanding.load(Ordering::Relaxed),
+                        last_checked: health.last_checked,
+                        last_error: health.last_error.clone(),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Why no member of an upstream could be selected
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unavailable {
+    CircuitOpen,
+    /// Every member failed its health checks
+    Unhealthy,
+}
+
+impl From<CircuitOpen> for Unavailable {
+    fn from(_: CircuitOpen) -> Self {
+        Unavailable::CircuitOpen
+    }
+}
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unavailable::CircuitOpen => write!(f, "circuit breaker is open"),
+            Unavailable::Unhealthy => write!(f, "no healthy members"),
+        }
+    }
+}
+
+/// Point-in-time view of an upstream for the admin endpoint
+#[derive(Debug, Serialize)]
+pub struct UpstreamStatus {
+    pub name: String,
+    /// Whether requests can currently be sent to the upstream
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub circuit_breaker: Option<BreakerStatus>,
+    pub members: Vec<MemberStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MemberStatus {
+    pub name: String,
+    pub healthy: bool,
+    /// Requests currently in flight
+    pub outstanding: usize,
+    /// Unix time of the last completed health check
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_checked: Option<u64>,
+    /// Why the last health check failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// Pool state for one upstream
+pub struct UpstreamState {
+    members: Vec<MemberConfig>,
+    stats: Vec<MemberStats>,
+    /// Current weights for smooth weighted round-robin
+    current_weights: Mutex<Vec<i64>>,
+    breaker: CircuitBreaker,
+    created_at: Instant,
+}
+
+#[derive(Default)]
+struct MemberStats {
+    outstanding: AtomicUsize,
+    /// Milliseconds after `created_at` of the last 429, plus one (0 = never)
+    last_429: AtomicU64,
+    health: Mutex<MemberHealth>,
+}
+
+/// Members start out healthy and are only marked unhealthy by failed checks
+#[derive(Default)]
+struct MemberHealth {
+    unhealthy: bool,
+    consecutive_failures: u32,
+    consecutive_successes: u32,
+    last_started: Option<Instant>,
+    last_checked: Option<u64>,
+    last_error: Option<String>,
+}
+
+impl UpstreamState {
+    fn new(members: Vec<MemberConfig>) -> Self {
+        Self {
+            stats: members.iter().map(|_| MemberStats::default()).collect(),
+            current_weights: Mutex::new(vec![0; members.len()]),
+            members,
+            breaker: CircuitBreaker::default(),
+            created_at: Instant::now(),
+        }
+    }
+
+    /// Which members may receive requests. Health is ignored for upstreams
+    /// without health checks, e.g. after checks are removed by a reload.
+    fn usable_members(&self, upstream: &UpstreamConfig) -> Vec<bool> {
+        self.stats
+            .iter()
+            .map(|stats| upstream.health_check.is_none() || !stats.health.lock().unwrap().unhealthy)
+            .collect()
+    }
+
+    fn choose(&self, strategy: BalanceStrategy, usable: &[bool]) -> usize {
+        if self.members.len() == 1 {
+            return 0;
+        }
+
+        match strategy {
+            BalanceStrategy::WeightedRoundRobin => self.weighted_round_robin(|i| usable[i]),
+            BalanceStrategy::LeastOutstanding => (0..self.members.len())
+                .filter(|&i| usable[i])
+                .min_by(|&a, &b| {
+                    // Compare outstanding / weight without floating point
+                    let load = |i: usize| self.stats[i].outstanding.load(Ordering::Relaxed) as u64;
+                    let weight = |i: usize| self.members[i].weight as u64;
+                    (load(a) * weight(b)).cmp(&(load(b) * weight(a)))
+                })
+                .unwrap_or(0),
+            BalanceStrategy::LeastRecent429 => {
+                let last_429 = |i: usize| self.stats[i].last_429.load(Ordering::Relaxed);
+                let usable_indexes = (0..self.members.len()).filter(|&i| usable[i]);
+                let oldest = usable_indexes.map(last_429).min().unwrap_or(0);
+                self.weighted_round_robin(|i| usable[i] && last_429(i) == oldest)
+            }
+        }
+    }
+
+    /// Smooth weighted round-robin (as in nginx) over the members accepted by `filter`
+    fn weighted_round_robin(&self, filter: impl Fn(usize) -> bool) -> usize {
+        let mut current = self.current_weights.lock().unwrap();
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+
+        for i in (0..self.members.len()).filter(|&i| filter(i)) {
+            let weight = self.members[i].weight as i64;
+            current[i] += weight;
+            total += weight;
+            if best.is_none_or(|b| current[i] > current[b]) {
+                best = Some(i);
+            }
+        }
+
+        let best = best.unwrap_or(0);
+        current[best] -= total;
+        best
+    }
+
+    fn record_429(&self, index: usize) {
+        let elapsed = self.created_at.elapsed().as_millis() as u64 + 1;
+        self.stats[index].last_429.store(elapsed, Ordering::Relaxed);
+    }
+}
+
+/// The member chosen for a request. Counts as an outstanding request for the
+/// member until dropped, so it should live as long as the response body.
+pub struct SelectedMember {
+    pub upstream: String,
+    pub name: String,
+    pub url: String,
+    api_key: Option<String>,
+    auth_header: String,
+    /// Whether the upstream has explicitly configured members
+    pub is_pooled: bool,
+    breaker: Option<CircuitBreakerConfig>,
+    /// Whether this request is a half-open circuit breaker probe
+    probe: bool,
+    recorded: AtomicBool,
+    state: Arc<UpstreamState>,
+    index: usize,
+}
+
+impl SelectedMember {
+    /// Returns the full URL for a given API path (e.g., "/chat/completions")
+    pub fn url_for_path(&self, path: &str) -> String {
+        format!("{}{}", self.url.trim_end_matches('/'), path)
+    }
+
+    /// Replace the client's credentials with the member's, if it has one
+    pub fn apply_credentials(&self, headers: &mut http::HeaderMap) {
+        set_credentials(headers, self.api_key.as_deref(), &self.auth_header);
+    }
+
+    /// Record the upstream's response status for load balancing and the
+    /// circuit breaker. Returns the breaker's state change, if any.
+    pub fn record_status(&self, status: http::StatusCode) -> Option<Transition> {
+        if status == http::StatusCode::TOO_MANY_REQUESTS {
+            self.state.record_429(self.index);
+        }
+        let failed = self
+            .breaker
+            .as_ref()
+            .is_some_and(|config| config.failure_statuses.contains(&status.as_u16()));
+        self.record_outcome(!failed)
+    }
+
+    /// Record a transport error (connect failure, timeout) for the circuit breaker
+    pub fn record_error(&self) -> Option<Transition> {
+        self.record_outcome(false)
+    }
+
+    fn record_outcome(&self, success: bool) -> Option<Transition> {
+        let config = self.breaker.as_ref()?;
+        self.recorded.store(true, Ordering::Relaxed);
+        self.state.breaker.record(config, success, self.probe)
+    }
+}
+
+fn set_credentials(headers: &mut http::HeaderMap, api_key: Option<&str>, auth_header: &str) {
+    let Some(api_key) = api_key else {
+        return;
+    };
+    let Ok(name) = http::HeaderName::from_bytes(auth_header.as_bytes()) else {
+        return;
+    };
+
+    let value = if name == http::header::AUTHORIZATION {
+        format!("Bearer {}", api_key)
+    } else {
+        api_key.to_string()
+    };
+    if let Ok(mut value) = http::HeaderValue::from_str(&value) {
+        value.set_sensitive(true);
+        auth::strip_credentials(headers);
+        headers.insert(name, value);
+    }
+}
+
+/// A health check to run against one member of an upstream
+pub struct HealthCheck {
+    pub upstream: String,
+    pub member: String,
+    /// Full URL to GET
+    pub url: String,
+    /// The member's credentials
+    pub headers: http::HeaderMap,
+    pub config: HealthCheckConfig,
+    state: Arc<UpstreamState>,
+    index: usize,
+}
+
+impl HealthCheck {
+    /// Record the check's outcome. Returns the member's new health when it
+    /// changed.
+    pub fn record(&self, result: Result<(), String>) -> Option<bool> {
+        let mut health = self.state.stats[self.index].health.lock().unwrap();
+        health.last_checked = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs());
+
+        match result {
+            Ok(()) => {
+                health.last_error = None;
+                health.consecutive_failures = 0;
+                health.consecutive_successes += 1;
+                if health.unhealthy && health.consecutive_successes >= self.config.healthy_threshold {
+                    health.unhealthy = false;
+                    return Some(true);
+                }
+            }
+            Err(error) => {
+                health.last_error = Some(error);
+                health.consecutive_successes = 0;
+                health.consecutive_failures += 1;
+                if !health.unhealthy && health.consecutive_failures >= self.config.unhealthy_threshold {
+                    health.unhealthy = true;
+                    return Some(false);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Drop for SelectedMember {
+    fn drop(&mut self) {
+        if self.probe && !self.recorded.load(Ordering::Relaxed) {
+            self.state.breaker.release_probe();
+        }
+        self.state.stats[self.index]
+            .outstanding
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream(balance: BalanceStrategy) -> UpstreamConfig {
+        UpstreamConfig {
+            name: "pool".to_string(),
+            url: "http://localhost:8000".to_string(),
+            auth_header: "authorization".to_string(),
+            balance,
+            members: vec![
+                MemberConfig {
+                    name: "a".to_string(),
+                    url: None,
+                    api_key: Some("key-a".to_string()),
+                    weight: 2,
+                },
+                MemberConfig {
+                    name: "b".to_string(),
+                    url: Some("http://localhost:9000".to_string()),
+                    api_key: Some("key-b".to_string()),
+                    weight: 1,
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_weighted_round_robin() {
+        let registry = UpstreamRegistry::default();
+        let upstream = upstream(BalanceStrategy::WeightedRoundRobin);
+        let picks: Vec<String> = (0..6).map(|_| registry.select(&upstream).unwrap().name.clone()).collect();
+        assert_eq!(picks, ["a", "b", "a", "a", "b", "a"]);
+    }
+
+    #[test]
+    fn test_least_outstanding() {
+        let registry = UpstreamRegistry::default();
+        let upstream = upstream(BalanceStrategy::LeastOutstanding);
+
+        let first = registry.select(&upstream).unwrap();
+        let second = registry.select(&upstream).unwrap();
+        let third = registry.select(&upstream).unwrap();
+        // a has twice the weight so it takes two in-flight requests to b's one
+        assert_eq!([&first.name, &second.name, &third.name], ["a", "b", "a"]);
+
+        drop(second);
+        assert_eq!(registry.select(&upstream).unwrap().name, "b");
+        assert_eq!(registry.select(&upstream).unwrap().name, "b");
+    }
+
+    #[test]
+    fn test_least_recent_429() {
+        let registry = UpstreamRegistry::default();
+        let upstream = upstream(BalanceStrategy::LeastRecent429);
+
+        let member = registry.select(&upstream).unwrap();
+        assert_eq!(member.name, "a");
+        member.record_status(http::StatusCode::TOO_MANY_REQUESTS);
+
+        for _ in 0..3 {
+            assert_eq!(registry.select(&upstream).unwrap().name, "b");
+        }
+    }
+
+    #[test]
+    fn test_apply_credentials() {
+        let registry = UpstreamRegistry::default();
+        let mut upstream = upstream(BalanceStrategy::WeightedRoundRobin);
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert("authorization", http::HeaderValue::from_static("Bearer client"));
+        let member = registry.select(&upstream).unwrap();
+        member.apply_credentials(&mut headers);
+        assert_eq!(headers["authorization"], "Bearer key-a");
+        assert_eq!(member.url_for_path("/chat/completions"), "http://localhost:8000/chat/completions");
+
+        upstream.auth_header = "api-key".to_string();
+        let mut headers = http::HeaderMap::new();
+        headers.insert("authorization", http::HeaderValue::from_static("Bearer client"));
+        headers.insert("x-api-key", http::HeaderValue::from_static("client"));
+        let member = registry.select(&upstream).unwrap();
+        member.apply_credentials(&mut headers);
+        assert_eq!(member.url, "http://localhost:9000");
+        assert_eq!(headers["api-key"], "key-b");
+        assert!(!headers.contains_key("authorization"));
+        assert!(!headers.contains_key("x-api-key"));
+    }
+
+    #[test]
+    fn test_state_rebuilt_when_members_change() {
+        let registry = UpstreamRegistry::default();
+        let mut upstream = upstream(BalanceStrategy::WeightedRoundRobin);
+        let state = registry.state(&upstream);
+        assert!(Arc::ptr_eq(&state, &registry.state(&upstream)));
+
+        upstream.members[1].weight = 5;
+        assert!(!Arc::ptr_eq(&state, &registry.state(&upstream)));
+    }
+
+    #[test]
+    fn test_open_breaker_fails_fast() {
+        let registry = UpstreamRegistry::default();
+        let mut upstream = upstream(BalanceStrategy::WeightedRoundRobin);
+        upstream.circuit_breaker = Some(CircuitBreakerConfig {
+            consecutive_failures: 2,
+            ..Default::default()
+        });
+
+        let member = registry.select(&upstream).unwrap();
+        assert_eq!(member.record_status(http::StatusCode::BAD_GATEWAY), None);
+        // Rate limiting isn't an upstream failure
+        assert_eq!(member.record_status(http::StatusCode::TOO_MANY_REQUESTS), None);
+        assert_eq!(member.record_error(), None);
+        assert_eq!(member.record_error(), Some(Transition::Opened));
+
+        assert!(registry.select(&upstream).is_err());
+        assert_eq!(registry.status(&upstream).circuit_breaker.unwrap().state, "open");
+    }
+
+    #[test]
+    fn test_unhealthy_members_are_skipped() {
+        let registry = UpstreamRegistry::default();
+        let mut upstream = upstream(BalanceStrategy::WeightedRoundRobin);
+        upstream.health_check = Some(HealthCheckConfig {
+            unhealthy_threshold: 2,
+            ..Default::default()
+        });
+
+        let checks = registry.due_health_checks(&upstream);
+        assert_eq!(checks.len(), 2);
+        assert_eq!(checks[0].url, "http://localhost:8000/models");
+        assert_eq!(checks[1].url, "http://localhost:9000/models");
+        assert_eq!(checks[1].headers["authorization"], "Bearer key-b");
+        // Checks that just ran aren't due again until the interval passes
+        assert!(registry.due_health_checks(&upstream).is_empty());
+
+        assert_eq!(checks[0].record(Err("503".to_string())), None);
+        assert_eq!(checks[0].record(Err("503".to_string())), Some(false));
+        for _ in 0..3 {
+            assert_eq!(registry.select(&upstream).unwrap().name, "b");
+        }
+
+        assert_eq!(checks[1].record(Err("timeout".to_string())), None);
+        assert_eq!(checks[1].record(Err("timeout".to_string())), Some(false));
+        assert_eq!(registry.select(&upstream).err(), Some(Unavailable::Unhealthy));
+        assert!(!registry.status(&upstream).healthy);
+
+        assert_eq!(checks[0].record(Ok(())), Some(true));
+        assert_eq!(registry.select(&upstream).unwrap().name, "a");
+    }
+}
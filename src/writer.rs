@@ -0,0 +1,120 @@
+//! A queue of records written out in batches on a background thread, so
+//! requests never wait on the disk. Used by the usage ledger and the capture
+//! file.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::time::{Duration, Instant};
+
+/// Where a `BackgroundWriter` writes its records
+pub trait BatchWrite<T>: Send + 'static {
+    /// Write out a batch of records. Errors are logged by the implementation,
+    /// since there's no one to return them to.
+    fn write_batch(&mut self, batch: &[T]);
+}
+
+enum Message<T> {
+    Record(Box<T>),
+    /// Write everything queued so far, then acknowledge
+    Flush(SyncSender<()>),
+}
+
+/// Queues records for a writer thread, which writes a batch once it's full
+/// or its first record has waited `flush_interval`
+pub struct BackgroundWriter<T> {
+    /// Used in the thread's name and in logs, e.g. `ledger`
+    name: &'static str,
+    sender: SyncSender<Message<T>>,
+    dropped: AtomicU64,
+}
+
+impl<T: Send + 'static> BackgroundWriter<T> {
+    /// Start a thread writing to `writer`
+    pub fn spawn(
+        name: &'static str,
+        writer: impl BatchWrite<T>,
+        queue_size: usize,
+        batch_size: usize,
+        flush_interval: Duration,
+    ) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        std::thread::Builder::new()
+            .name(format!("{}-writer", name))
+            .spawn(move || write_loop(writer, receiver, batch_size, flush_interval))
+            .unwrap_or_else(|e| panic!("failed to spawn {} writer thread: {}", name, e));
+
+        Self {
+            name,
+            sender,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue a record to be written. Never blocks; drops the record if the
+    /// queue is full.
+    pub fn record(&self, record: T) {
+        match self.sender.try_send(Message::Record(Box::new(record))) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped == 1 || dropped.is_multiple_of(1000) {
+                    log::warn!("The {} queue is full, {} records dropped so far", self.name, dropped);
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => log::error!("The {} writer has stopped", self.name),
+        }
+    }
+
+    /// Block until every queued record has been written
+    pub fn flush(&self) {
+        let (ack, done) = mpsc::sync_channel(1);
+        if self.sender.send(Message::Flush(ack)).is_ok() {
+            let _ = done.recv();
+        }
+    }
+}
+
+fn write_loop<T>(
+    mut writer: impl BatchWrite<T>,
+    receiver: mpsc::Receiver<Message<T>>,
+    batch_size: usize,
+    flush_interval: Duration,
+) {
+    let mut batch: Vec<T> = Vec::with_capacity(batch_size);
+    let mut deadline = Instant::now();
+
+    let mut write = |batch: &mut Vec<T>| {
+        if !batch.is_empty() {
+            writer.write_batch(batch);
+            batch.clear();
+        }
+    };
+    loop {
+        let message = if batch.is_empty() {
+            receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        };
+
+        match message {
+            Ok(Message::Record(record)) => {
+                if batch.is_empty() {
+                    deadline = Instant::now() + flush_interval;
+                }
+                batch.push(*record);
+                if batch.len() >= batch_size {
+                    write(&mut batch);
+                }
+            }
+            Ok(Message::Flush(ack)) => {
+                write(&mut batch);
+                let _ = ack.send(());
+            }
+            Err(RecvTimeoutError::Timeout) => write(&mut batch),
+            Err(RecvTimeoutError::Disconnected) => {
+                write(&mut batch);
+                return;
+            }
+        }
+    }
+}